@@ -0,0 +1,509 @@
+use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap as Map,
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::errors::{Error, Result};
+
+/// The registry of images that don't name one.
+const DEFAULT_REGISTRY: &str = "docker.io";
+
+/// The server that docker stores the credentials of Docker Hub under.
+const DEFAULT_REGISTRY_SERVER: &str = "https://index.docker.io/v1/";
+
+/// A username and password for a registry.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    /// Whether podman finds these credentials on its own, since they are
+    /// stored in the `auths` of one of its auth files.
+    pub podman_reads: bool,
+}
+
+/// An `auth.json` of podman or a `config.json` of docker, which share their
+/// format.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthFile {
+    #[serde(default)]
+    auths: Map<String, AuthEntry>,
+    #[serde(default)]
+    cred_helpers: Map<String, String>,
+    creds_store: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AuthEntry {
+    /// `username:password`, base64 encoded.
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(rename = "identitytoken")]
+    identity_token: Option<String>,
+}
+
+/// The reply of `docker-credential-<helper> get`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Finds the credentials for the registry of an image, in the auth files
+/// that podman and docker use. The first file with credentials for the
+/// registry wins.
+///
+/// Files that can't be read are skipped with a warning, so that images can
+/// still be pulled without credentials. So are credential helpers that fail,
+/// in favor of the credentials stored in the file itself.
+pub fn credentials(image: &str) -> Option<Credentials> {
+    let repository = repository(image);
+
+    for (path, podman_reads) in auth_file_paths() {
+        match file_credentials(&path, &repository) {
+            Ok(Some(mut credentials)) => {
+                credentials.podman_reads &= podman_reads;
+                return Some(credentials);
+            }
+            Ok(None) => (),
+            Err(err) => eprintln!(
+                "WARNING: ignoring the credentials in {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+
+    None
+}
+
+/// The credentials for a repository in a single auth file, if it exists.
+fn file_credentials(path: &Path, repository: &str) -> Result<Option<Credentials>> {
+    let file = match fs::read_to_string(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let file: AuthFile = serde_json::from_str(&file).map_err(Error::from)?;
+
+    file.credentials(repository)
+}
+
+/// The auth files, in the order podman reads them, and whether they're
+/// podman's own.
+fn auth_file_paths() -> Vec<(PathBuf, bool)> {
+    let mut paths = Vec::new();
+
+    if let Some(path) = env::var_os("REGISTRY_AUTH_FILE") {
+        paths.push((PathBuf::from(path), true));
+    }
+    if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
+        paths.push((Path::new(&runtime_dir).join("containers/auth.json"), true));
+    }
+    if let Some(home) = env::var_os("HOME") {
+        paths.push((Path::new(&home).join(".docker/config.json"), false));
+    }
+
+    paths
+}
+
+/// The repository of an image, including its registry and without its tag
+/// or digest, like `docker.io/library/redis`.
+fn repository(image: &str) -> String {
+    let image = image.split('@').next().unwrap_or(image);
+
+    // A tag follows the last colon, unless that colon is part of a port.
+    let image = match image.rfind(':') {
+        Some(index) if !image[index..].contains('/') => &image[..index],
+        _ => image,
+    };
+
+    // The first component is a registry if it looks like a host name.
+    match image.split_once('/') {
+        Some((host, _)) if host.contains(&['.', ':'][..]) || host == "localhost" => {
+            normalize_registry(image)
+        }
+        Some(_) => format!("{}/{}", DEFAULT_REGISTRY, image),
+        None => format!("{}/library/{}", DEFAULT_REGISTRY, image),
+    }
+}
+
+/// Strips the scheme and API path of a key in an auth file, which may be a
+/// URL like `https://index.docker.io/v1/`, and names Docker Hub consistently.
+fn normalize_registry(key: &str) -> String {
+    let key = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let key = key
+        .trim_end_matches('/')
+        .trim_end_matches("/v1")
+        .trim_end_matches("/v2");
+
+    match key.split_once('/') {
+        Some((host, rest)) if is_docker_hub(host) => format!("{}/{}", DEFAULT_REGISTRY, rest),
+        None if is_docker_hub(key) => DEFAULT_REGISTRY.to_owned(),
+        _ => key.to_owned(),
+    }
+}
+
+fn is_docker_hub(host: &str) -> bool {
+    matches!(
+        host,
+        "docker.io" | "index.docker.io" | "registry-1.docker.io"
+    )
+}
+
+impl AuthFile {
+    fn credentials(&self, repository: &str) -> Result<Option<Credentials>> {
+        let registry = repository.split('/').next().unwrap_or(repository);
+
+        let helper = self
+            .cred_helpers
+            .iter()
+            .find(|(key, _)| normalize_registry(key) == registry)
+            .map(|(_, helper)| helper)
+            .or(self.creds_store.as_ref());
+
+        if let Some(helper) = helper {
+            match run_helper(helper, registry) {
+                Ok(Some(credentials)) => return Ok(Some(credentials)),
+                Ok(None) => (),
+                Err(err) => eprintln!(
+                    "WARNING: ignoring credential helper {} for {}: {}",
+                    helper, registry, err
+                ),
+            }
+        }
+
+        // Keys may also name a namespace or repository of a registry, the
+        // most specific one wins.
+        let entry = self
+            .auths
+            .iter()
+            .map(|(key, entry)| (normalize_registry(key), entry))
+            .filter(|(key, _)| {
+                repository == key
+                    || repository.starts_with(key.as_str())
+                        && repository[key.len()..].starts_with('/')
+            })
+            .max_by_key(|(key, _)| key.len());
+
+        match entry {
+            Some((key, entry)) => entry
+                .credentials()
+                .map_err(|err| err.context(format!("auths.{}", key))),
+            None => Ok(None),
+        }
+    }
+}
+
+impl AuthEntry {
+    fn credentials(&self) -> Result<Option<Credentials>> {
+        if let Some(ref auth) = self.auth {
+            if !auth.is_empty() {
+                let auth = BASE64
+                    .decode(auth.trim())
+                    .map_err(|err| anyhow!("auth is not valid base64: {}", err))?;
+                let auth =
+                    String::from_utf8(auth).map_err(|_| anyhow!("auth is not valid utf-8"))?;
+
+                let (username, password) = auth
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("auth is not in the form username:password"))?;
+                return Ok(Some(Credentials {
+                    username: username.to_owned(),
+                    password: password.to_owned(),
+                    podman_reads: true,
+                }));
+            }
+        }
+
+        // Registries exchange identity tokens for access tokens, podman
+        // expects them as the password of a placeholder user.
+        if let Some(ref identity_token) = self.identity_token {
+            return Ok(Some(Credentials {
+                username: "<token>".to_owned(),
+                password: identity_token.clone(),
+                podman_reads: true,
+            }));
+        }
+
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => Ok(Some(Credentials {
+                username: username.clone(),
+                password: password.clone(),
+                podman_reads: true,
+            })),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Asks a docker credential helper for the credentials of a registry.
+fn run_helper(helper: &str, registry: &str) -> Result<Option<Credentials>> {
+    let program = format!("docker-credential-{}", helper);
+    let server = if registry == DEFAULT_REGISTRY {
+        DEFAULT_REGISTRY_SERVER
+    } else {
+        registry
+    };
+
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| Error::from(err).context(format!("could not run {}", program)))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(server.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        // Helpers report missing credentials on standard output.
+        let message = String::from_utf8_lossy(&output.stdout);
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+
+        return Err(anyhow!(
+            "{} get failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)
+        .map_err(|err| Error::from(err).context(format!("{} get", program)))?;
+
+    Ok(Some(Credentials {
+        username: credentials.username,
+        password: credentials.secret,
+        podman_reads: false,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    /// Serializes the tests that point the auth files somewhere else, since
+    /// they are found through environment variables.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Looks up the credentials for `image` with `registry_file` as the file
+    /// named by `REGISTRY_AUTH_FILE` and `runtime_file` as the `auth.json`
+    /// in `XDG_RUNTIME_DIR`, either of which may be missing.
+    fn lookup(
+        registry_file: Option<&str>,
+        runtime_file: Option<&str>,
+        image: &str,
+    ) -> Option<(String, String)> {
+        let _guard = ENV.lock().unwrap_or_else(|err| err.into_inner());
+        let dir = TempDir::new().unwrap();
+
+        let registry_path = dir.path().join("auth.json");
+        if let Some(contents) = registry_file {
+            fs::write(&registry_path, contents).unwrap();
+        }
+        if let Some(contents) = runtime_file {
+            fs::create_dir(dir.path().join("containers")).unwrap();
+            fs::write(dir.path().join("containers/auth.json"), contents).unwrap();
+        }
+
+        env::set_var("REGISTRY_AUTH_FILE", &registry_path);
+        env::set_var("XDG_RUNTIME_DIR", dir.path());
+        env::set_var("HOME", dir.path());
+
+        credentials(image).map(|credentials| (credentials.username, credentials.password))
+    }
+
+    fn pair(username: &str, password: &str) -> Option<(String, String)> {
+        Some((username.to_owned(), password.to_owned()))
+    }
+
+    #[test]
+    fn repository_defaults_to_docker_hub() {
+        assert_eq!(repository("redis"), "docker.io/library/redis");
+        assert_eq!(repository("redis:7"), "docker.io/library/redis");
+        assert_eq!(repository("bitnami/redis:7"), "docker.io/bitnami/redis");
+        assert_eq!(
+            repository("redis@sha256:0123456789abcdef"),
+            "docker.io/library/redis"
+        );
+    }
+
+    #[test]
+    fn repository_tells_tags_from_registry_ports() {
+        assert_eq!(
+            repository("registry.example.com:5000/app"),
+            "registry.example.com:5000/app"
+        );
+        assert_eq!(
+            repository("registry.example.com:5000/app:1.0"),
+            "registry.example.com:5000/app"
+        );
+        assert_eq!(repository("localhost:5000/app:1.0"), "localhost:5000/app");
+        assert_eq!(repository("localhost/app:latest"), "localhost/app");
+        assert_eq!(repository("quay.io/team/app:1.0"), "quay.io/team/app");
+    }
+
+    #[test]
+    fn repository_names_docker_hub_consistently() {
+        assert_eq!(
+            repository("docker.io/library/redis"),
+            "docker.io/library/redis"
+        );
+        assert_eq!(
+            repository("index.docker.io/bitnami/redis"),
+            "docker.io/bitnami/redis"
+        );
+        assert_eq!(
+            repository("registry-1.docker.io/bitnami/redis:7"),
+            "docker.io/bitnami/redis"
+        );
+    }
+
+    #[test]
+    fn normalizes_registry_keys() {
+        assert_eq!(
+            normalize_registry("https://index.docker.io/v1/"),
+            "docker.io"
+        );
+        assert_eq!(normalize_registry("index.docker.io"), "docker.io");
+        assert_eq!(normalize_registry("registry-1.docker.io"), "docker.io");
+        assert_eq!(normalize_registry("docker.io"), "docker.io");
+        assert_eq!(normalize_registry("docker.io/bitnami"), "docker.io/bitnami");
+        assert_eq!(
+            normalize_registry("https://registry.example.com:5000/v2/"),
+            "registry.example.com:5000"
+        );
+        assert_eq!(
+            normalize_registry("http://localhost:5000"),
+            "localhost:5000"
+        );
+        assert_eq!(normalize_registry("quay.io/team"), "quay.io/team");
+    }
+
+    #[test]
+    fn registry_auth_file_comes_first() {
+        let registry_file = r#"{"auths": {"quay.io": {"username": "alice", "password": "a"}}}"#;
+        let runtime_file = r#"{"auths": {
+            "quay.io": {"username": "bob", "password": "b"},
+            "ghcr.io": {"username": "bob", "password": "b"}
+        }}"#;
+
+        assert_eq!(
+            lookup(Some(registry_file), Some(runtime_file), "quay.io/app"),
+            pair("alice", "a")
+        );
+        // Files without credentials for the registry are passed over.
+        assert_eq!(
+            lookup(Some(registry_file), Some(runtime_file), "ghcr.io/app"),
+            pair("bob", "b")
+        );
+    }
+
+    #[test]
+    fn most_specific_auths_key_wins() {
+        let file = r#"{"auths": {
+            "https://index.docker.io/v1/": {"username": "hub", "password": "a"},
+            "docker.io/team": {"username": "team", "password": "b"},
+            "docker.io/team/app": {"username": "app", "password": "c"}
+        }}"#;
+
+        assert_eq!(lookup(Some(file), None, "team/app:1.0"), pair("app", "c"));
+        assert_eq!(lookup(Some(file), None, "team/other"), pair("team", "b"));
+        assert_eq!(lookup(Some(file), None, "team-x/app"), pair("hub", "a"));
+        assert_eq!(lookup(Some(file), None, "redis"), pair("hub", "a"));
+    }
+
+    #[test]
+    fn auth_is_decoded_from_base64() {
+        let file = r#"{"auths": {"quay.io": {"auth": "YWxpY2U6c2VjcmV0"}}}"#;
+        assert_eq!(
+            lookup(Some(file), None, "quay.io/app"),
+            pair("alice", "secret")
+        );
+
+        // An invalid entry is skipped like the rest of its file.
+        let invalid = r#"{"auths": {"quay.io": {"auth": "not base64!"}}}"#;
+        let runtime_file = r#"{"auths": {"quay.io": {"auth": "Ym9iOmh1bnRlcjI="}}}"#;
+        assert_eq!(
+            lookup(Some(invalid), Some(runtime_file), "quay.io/app"),
+            pair("bob", "hunter2")
+        );
+    }
+
+    #[test]
+    fn identity_tokens_are_passed_as_passwords() {
+        let file = r#"{"auths": {"quay.io": {"identitytoken": "token"}}}"#;
+        assert_eq!(
+            lookup(Some(file), None, "quay.io/app"),
+            pair("<token>", "token")
+        );
+    }
+
+    #[test]
+    fn missing_and_bad_files_are_skipped() {
+        let runtime_file = r#"{"auths": {"quay.io": {"username": "bob", "password": "b"}}}"#;
+
+        assert_eq!(lookup(None, None, "quay.io/app"), None);
+        assert_eq!(
+            lookup(None, Some(runtime_file), "quay.io/app"),
+            pair("bob", "b")
+        );
+        assert_eq!(
+            lookup(Some("{not json"), Some(runtime_file), "quay.io/app"),
+            pair("bob", "b")
+        );
+    }
+
+    #[test]
+    fn podman_only_reads_its_own_auth_files() {
+        let _guard = ENV.lock().unwrap_or_else(|err| err.into_inner());
+        let dir = TempDir::new().unwrap();
+        let auths = r#"{"auths": {"quay.io": {"username": "alice", "password": "a"}}}"#;
+        fs::create_dir(dir.path().join(".docker")).unwrap();
+        fs::write(dir.path().join(".docker/config.json"), auths).unwrap();
+        fs::write(dir.path().join("auth.json"), auths).unwrap();
+
+        env::set_var("REGISTRY_AUTH_FILE", dir.path().join("missing.json"));
+        env::set_var("XDG_RUNTIME_DIR", dir.path().join("missing"));
+        env::set_var("HOME", dir.path());
+        assert!(!credentials("quay.io/app").unwrap().podman_reads);
+
+        env::set_var("REGISTRY_AUTH_FILE", dir.path().join("auth.json"));
+        assert!(credentials("quay.io/app").unwrap().podman_reads);
+    }
+
+    #[test]
+    fn failing_credential_helpers_fall_back_to_auths() {
+        let file = r#"{
+            "credsStore": "pod-compose-missing",
+            "credHelpers": {"ghcr.io": "pod-compose-missing"},
+            "auths": {
+                "quay.io": {"username": "alice", "password": "a"},
+                "ghcr.io": {"username": "alice", "password": "b"}
+            }
+        }"#;
+
+        assert_eq!(lookup(Some(file), None, "quay.io/app"), pair("alice", "a"));
+        assert_eq!(lookup(Some(file), None, "ghcr.io/app"), pair("alice", "b"));
+        assert_eq!(lookup(Some(file), None, "docker.io/app"), None);
+    }
+}
@@ -1,3 +1,4 @@
 pub use podman::PodmanBackend;
 
+mod auth;
 mod podman;
@@ -1,85 +1,188 @@
-use anyhow::{anyhow, Result};
+use anyhow::anyhow;
 use ignore::WalkBuilder;
 use number_prefix::NumberPrefix;
-use std::{collections::BTreeMap as Map, fs::OpenOptions};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap as Map, VecDeque},
+    ffi::OsStr,
+    fs::OpenOptions,
+    net::IpAddr,
+    os::unix::fs::MetadataExt,
+    process::Command,
+    time::Duration,
+};
 use tar::Builder as TarBuilder;
 use tempfile::TempDir;
 use varlink::Connection;
 
 use podman_varlink::{
-    AuthConfig, BuildInfo, Create as CreateContainer, Error, ErrorKind, VarlinkClient,
-    VarlinkClientInterface,
+    AuthConfig, BuildInfo, BuildOptions, Create as CreateContainer, Podman, PodmanError,
 };
 
+use super::auth;
 use crate::{
+    errors::{Error, Result},
     models::{
-        Container, ContainerId, ContainerName, ContainerSpec, ContainerStatus, Image,
-        ImageBuildSpec, ImageId, ImageName, PullPolicy,
+        Container, ContainerId, ContainerName, ContainerSpec, ContainerStatus, HealthStatus,
+        HealthcheckTest, Image, ImageBuildSpec, ImageId, ImageName, Network, NetworkName,
+        NetworkSpec, PullPolicy, RestartPolicy,
     },
     services::ContainerBackend,
 };
 
+/// How many lines of build output are included in a build failure.
+const BUILD_LOG_TAIL: usize = 10;
+
+/// How often podman checks whether a container has stopped, in milliseconds.
+const WAIT_INTERVAL_MS: i64 = 250;
+
+/// The first podman version with `podman network connect --alias`.
+const NETWORK_CONNECT_VERSION: (u32, u32) = (2, 2);
+
 pub struct PodmanBackend {
-    client: VarlinkClient,
+    podman: Podman,
+    /// Whether containers can be connected to networks with aliases, which
+    /// needs podman 2.2 and root.
+    network_connect: bool,
 }
 
 impl PodmanBackend {
     pub fn connect() -> Result<PodmanBackend> {
-        let connection = Connection::with_activate(r#"podman varlink "$VARLINK_ADDRESS""#)?;
-        let client = VarlinkClient::new(connection.clone());
+        let connection = Connection::with_activate(r#"podman varlink "$VARLINK_ADDRESS""#)
+            .map_err(|err| Error::Connection(err.into()))?;
+        let mut podman = Podman::new(connection);
+
+        let version = podman.get_version()?;
+        let rootless = std::fs::metadata("/proc/self")?.uid() != 0;
+        let network_connect = version_at_least(&version, NETWORK_CONNECT_VERSION) && !rootless;
+
+        Ok(PodmanBackend {
+            podman,
+            network_connect,
+        })
+    }
+}
+
+/// Whether a podman version like `2.2.1` or `2.2.0-rc1` is at least
+/// `minimum`.
+fn version_at_least(version: &str, minimum: (u32, u32)) -> bool {
+    let mut parts = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    let major = parts.next().unwrap_or(0);
+    let minor = parts.next().unwrap_or(0);
+
+    (major, minor) >= minimum
+}
+
+/// Runs a podman command, for things that are missing from the varlink
+/// interface. Returns the standard output of the command.
+fn podman_command<S: AsRef<OsStr>>(args: &[S]) -> Result<Vec<u8>> {
+    let output = Command::new("podman").args(args).output()?;
+
+    if !output.status.success() {
+        let args = args
+            .iter()
+            .map(|arg| arg.as_ref().to_string_lossy())
+            .collect::<Vec<_>>();
+
+        return Err(anyhow!(
+            "podman {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(output.stdout)
+}
 
-        Ok(PodmanBackend { client })
+/// Formats a duration the way Go's `time.ParseDuration` expects it.
+fn go_duration(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
+}
+
+fn restart_policy(restart_policy: RestartPolicy) -> Option<String> {
+    match restart_policy {
+        RestartPolicy::No => None,
+        RestartPolicy::Always => Some("always".into()),
+        RestartPolicy::OnFailure { max_retries: None } => Some("on-failure".into()),
+        RestartPolicy::OnFailure {
+            max_retries: Some(max_retries),
+        } => Some(format!("on-failure:{}", max_retries)),
+        RestartPolicy::UnlessStopped => Some("unless-stopped".into()),
+    }
+}
+
+fn container_status(status: &str) -> ContainerStatus {
+    match status {
+        "configured" | "created" => ContainerStatus::Configured,
+        "running" => ContainerStatus::Running,
+        "restarting" => ContainerStatus::Restarting,
+        "paused" => ContainerStatus::Paused,
+        "stopping" => ContainerStatus::Stopping,
+        "exited" | "stopped" => ContainerStatus::Exited,
+        status => {
+            eprintln!("Unknown container status: {:?}", status);
+            ContainerStatus::Unknown
+        }
     }
 }
 
 impl ContainerBackend for PodmanBackend {
     fn get_image(&mut self, name: &ImageName) -> Result<Option<Image>> {
-        let reply = self.client.get_image(name.0.clone()).call();
-
-        let reply = match reply {
-            Ok(reply) => reply,
-            Err(Error(ErrorKind::ImageNotFound(_), _, _)) => return Ok(None),
+        let image = match self.podman.get_image(&name.0) {
+            Ok(image) => image,
+            Err(PodmanError::ImageNotFound { .. }) => return Ok(None),
             Err(err) => Err(err)?,
         };
 
-        let labels = reply
-            .image
+        let labels = image
             .labels
             .map(|labels| labels.into_iter().collect())
             .unwrap_or_else(Default::default);
 
         Ok(Some(Image {
-            id: ImageId(reply.image.id),
+            id: ImageId(image.id),
             labels,
         }))
     }
 
-    fn pull_image(&mut self, name: &ImageName) -> Result<ImageId> {
-        let auth_config = AuthConfig {
-            username: None,
-            password: None,
+    fn pull_image(&mut self, name: &ImageName, quiet: bool) -> Result<ImageId> {
+        let auth_config = match auth::credentials(&name.0) {
+            Some(credentials) => AuthConfig::default()
+                .username(credentials.username)
+                .password(credentials.password),
+            None => AuthConfig::default(),
         };
 
-        let mut image_id = None;
+        let image_id = self.podman.pull_image(&name.0, auth_config, |line| {
+            if !quiet {
+                print!("{}", line)
+            }
+        })?;
 
-        for reply in self.client.pull_image(name.0.clone(), auth_config).more()? {
-            let reply = reply?.reply;
+        Ok(ImageId(image_id))
+    }
 
-            if let Some(logs) = reply.logs {
-                if !logs.is_empty() {
-                    for line in logs {
-                        print!("{}", line);
-                    }
-                }
+    fn push_image(&mut self, name: &ImageName, quiet: bool) -> Result<()> {
+        // `PushImage` takes no credentials, podman looks them up itself.
+        if let Some(credentials) = auth::credentials(&name.0) {
+            if !credentials.podman_reads {
+                eprintln!(
+                    "WARNING: the credentials for {} can't be passed to podman for a push, log in with podman login if it fails",
+                    name.0
+                );
             }
+        }
 
-            if !reply.id.is_empty() {
-                image_id = Some(reply.id);
+        self.podman.push_image(&name.0, &name.0, |line| {
+            if !quiet {
+                print!("{}", line)
             }
-        }
+        })?;
 
-        let image_id = ImageId(image_id.unwrap());
-        Ok(image_id)
+        Ok(())
     }
 
     fn build_image(&mut self, spec: &ImageBuildSpec, pull_policy: PullPolicy) -> Result<ImageId> {
@@ -134,72 +237,59 @@ impl ContainerBackend for PodmanBackend {
             .labels
             .iter()
             .map(|(key, value)| format!("{}={}", key, value))
-            .collect();
+            .collect::<Vec<_>>();
 
-        // TODO: are these values really correct?
+        // Podman builds with `PullNever` for anything it doesn't recognize.
         let pull_policy = match pull_policy {
-            PullPolicy::IfNotPresent => "IfNotPresent",
-            PullPolicy::Always => "Always",
-        };
-
-        let build_info = BuildInfo {
-            architecture: None,
-            addCapabilities: None,
-            additionalTags: None,
-            annotations: None,
-            buildArgs: None,
-            buildOptions: None,
-            cniConfigDir: None,
-            cniPluginDir: None,
-            compression: None,
-            contextDir: context_dir.into(),
-            defaultsMountFilePath: None,
-            devices: None,
-            dockerfiles: vec![dockerfile.into()],
-            dropCapabilities: None,
-            err: None,
-            forceRmIntermediateCtrs: None,
-            iidfile: None,
-            label: Some(labels),
-            layers: None,
-            nocache: Some(false),
-            os: None,
-            out: None,
-            output: spec.name.0.clone(),
-            outputFormat: None,
-            pullPolicy: Some(pull_policy.into()),
-            quiet: None,
-            remoteIntermediateCtrs: None,
-            reportWriter: None,
-            runtimeArgs: None,
-            signBy: None,
-            squash: None,
-            target: spec.target.clone(),
-            transientMounts: None,
+            PullPolicy::IfNotPresent => "PullIfMissing",
+            PullPolicy::Always => "PullAlways",
+            PullPolicy::Never => "PullNever",
         };
 
-        let mut image_id = None;
-
-        for reply in self.client.build_image(build_info).more()? {
-            let reply = reply?;
-
-            if let Some(logs) = reply.image.logs {
-                if !logs.is_empty() {
-                    for line in logs {
-                        print!("{}", line);
-                    }
+        // The other options are left at their zero values, which podman
+        // treats as unset.
+        let build_options = spec
+            .shm_size
+            .map(|shm_size| BuildOptions::default().shm_size(shm_size.to_string()));
+
+        let build_info = BuildInfo::default()
+            .build_options(build_options)
+            .context_dir(context_dir.into())
+            .dockerfiles(vec![dockerfile.into()])
+            .label(labels)
+            .nocache(false)
+            .output(spec.name.0.clone())
+            .pull_policy(pull_policy.to_owned())
+            .target(spec.target.clone());
+
+        // The end of the build output is kept to explain why a build failed.
+        let mut log_tail = VecDeque::new();
+        let result = self.podman.build_image(build_info, |log| {
+            print!("{}", log);
+
+            for line in log.lines() {
+                if log_tail.len() == BUILD_LOG_TAIL {
+                    log_tail.pop_front();
                 }
+                log_tail.push_back(line.to_owned());
             }
-
-            if !reply.image.id.is_empty() {
-                image_id = Some(reply.image.id);
+        });
+
+        let image_id = match result {
+            Ok(image_id) => image_id,
+            Err(err @ PodmanError::Connection(_)) => return Err(err.into()),
+            Err(err) => {
+                return Err(Error::BuildFailed {
+                    image: spec.name.0.clone(),
+                    reason: err.to_string(),
+                    log_tail: log_tail.into(),
+                })
             }
-        }
+        };
 
         temp_dir.close()?;
 
-        let image = ImageId(image_id.unwrap());
-        Ok(image)
+        Ok(ImageId(image_id))
     }
 
     fn list_containers(
@@ -208,14 +298,7 @@ impl ContainerBackend for PodmanBackend {
     ) -> Result<Map<ContainerName, Container>> {
         let mut containers = Map::new();
 
-        let reply = self.client.list_containers().call()?;
-
-        let reply_containers = match reply.containers {
-            Some(containers) => containers,
-            None => return Ok(containers),
-        };
-
-        'container_loop: for container in reply_containers {
+        'container_loop: for container in self.podman.list_containers()? {
             let container_labels = container.labels.unwrap_or_else(Default::default);
             for (label, value) in labels.iter() {
                 let container_label_value = container_labels.get(*label).map(|s| s.as_str());
@@ -224,20 +307,10 @@ impl ContainerBackend for PodmanBackend {
                 }
             }
 
-            let container_status = match container.status.as_str() {
-                "configured" => ContainerStatus::Configured,
-                "running" => ContainerStatus::Running,
-                "exited" => ContainerStatus::Exited,
-                status => {
-                    eprintln!("Unknown container status: {:?}", status);
-                    ContainerStatus::Unknown
-                }
-            };
-
             let container = Container {
                 id: ContainerId(container.id),
                 name: ContainerName(container.names),
-                status: container_status,
+                status: container_status(&container.status),
                 labels: container_labels.into_iter().collect(),
             };
             containers.insert(container.name.clone(), container);
@@ -246,145 +319,344 @@ impl ContainerBackend for PodmanBackend {
         Ok(containers)
     }
 
+    fn get_container(&mut self, name: &str) -> Result<Option<Container>> {
+        let container = match self.podman.get_container(name) {
+            Ok(container) => container,
+            Err(PodmanError::ContainerNotFound { .. }) => return Ok(None),
+            Err(err) => Err(err)?,
+        };
+
+        Ok(Some(Container {
+            id: ContainerId(container.id),
+            name: ContainerName(container.names),
+            status: container_status(&container.status),
+            labels: container
+                .labels
+                .map(|labels| labels.into_iter().collect())
+                .unwrap_or_else(Default::default),
+        }))
+    }
+
     fn create_container(&mut self, spec: ContainerSpec) -> Result<ContainerId> {
         let labels = spec
             .labels
             .iter()
             .map(|(key, value)| format!("{}={}", key, value))
-            .collect();
+            .collect::<Vec<_>>();
+
+        let healthcheck = spec.healthcheck.as_ref();
+        let healthcheck_command = match healthcheck.map(|h| &h.test) {
+            // Podman treats a JSON array as a command that is run directly,
+            // and anything else as a command that is run by a shell.
+            Some(HealthcheckTest::Command(command)) => Some(serde_json::to_string(command)?),
+            Some(HealthcheckTest::Shell(command)) => Some(command.clone()),
+            Some(HealthcheckTest::None) => Some("none".into()),
+            None => None,
+        };
+
+        let network = match spec.networks.len() {
+            0 => None,
+            _ => Some(
+                spec.networks
+                    .keys()
+                    .map(|name| name.0.as_str())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+        };
+
+        // Podman only supports a static address when the container is
+        // connected to a single network.
+        let ip_address = match spec.networks.values().next() {
+            Some(attachment) if spec.networks.len() == 1 => attachment.ipv4_address.clone(),
+            _ if spec.networks.values().any(|a| a.ipv4_address.is_some()) => {
+                return Err(anyhow!(
+                    "{} has a static ipv4_address but is connected to more than one network",
+                    spec.name.0
+                )
+                .into());
+            }
+            _ => None,
+        };
+
+        // `Create` has no field for network aliases, so the container is
+        // connected to those networks again with its aliases once it exists.
+        let mut network_aliases = Vec::new();
+        if self.network_connect {
+            for (network, attachment) in spec.networks.iter() {
+                if attachment.aliases.is_empty() {
+                    continue;
+                }
+                if attachment.ipv4_address.is_some() {
+                    eprintln!(
+                        "WARNING: ignoring the aliases of {} on {}, podman can't connect it again with its ipv4_address",
+                        spec.name.0, network.0
+                    );
+                    continue;
+                }
+                network_aliases.push((network.clone(), attachment.aliases.clone()));
+            }
+        }
+
+        let resources = spec.resources;
+        let security = spec.security;
 
-        let create_container = CreateContainer {
-            args: vec![spec.image_name.0],
-            addHost: Default::default(),
-            annotation: Default::default(),
-            attach: Default::default(),
-            blkioWeight: Default::default(),
-            blkioWeightDevice: Default::default(),
-            capAdd: Default::default(),
-            capDrop: Default::default(),
-            cgroupParent: Default::default(),
-            cidFile: Default::default(),
-            conmonPidfile: Default::default(),
-            command: Default::default(),
-            cpuPeriod: Default::default(),
-            cpuQuota: Default::default(),
-            cpuRtPeriod: Default::default(),
-            cpuRtRuntime: Default::default(),
-            cpuShares: Default::default(),
-            cpus: Default::default(),
-            cpuSetCpus: Default::default(),
-            cpuSetMems: Default::default(),
-            detach: Default::default(),
-            detachKeys: Default::default(),
-            device: Default::default(),
-            deviceReadBps: Default::default(),
-            deviceReadIops: Default::default(),
-            deviceWriteBps: Default::default(),
-            deviceWriteIops: Default::default(),
-            dns: Default::default(),
-            dnsOpt: Default::default(),
-            dnsSearch: Default::default(),
-            dnsServers: Default::default(),
-            entrypoint: Default::default(),
-            env: Default::default(),
-            envFile: Default::default(),
-            expose: Default::default(),
-            gidmap: Default::default(),
-            groupadd: Default::default(),
-            healthcheckCommand: Default::default(),
-            healthcheckInterval: Default::default(),
-            healthcheckRetries: Default::default(),
-            healthcheckStartPeriod: Default::default(),
-            healthcheckTimeout: Default::default(),
-            hostname: Default::default(),
-            imageVolume: Default::default(),
-            init: Default::default(),
-            initPath: Default::default(),
-            interactive: Default::default(),
-            ip: Default::default(),
-            ipc: Default::default(),
-            kernelMemory: Default::default(),
-            label: Some(labels),
-            labelFile: Default::default(),
-            logDriver: Default::default(),
-            logOpt: Default::default(),
-            macAddress: Default::default(),
-            memory: Default::default(),
-            memoryReservation: Default::default(),
-            memorySwap: Default::default(),
-            memorySwappiness: Default::default(),
-            name: Some(spec.name.0),
-            network: Default::default(),
-            noHosts: Default::default(),
-            oomKillDisable: Default::default(),
-            oomScoreAdj: Default::default(),
-            overrideArch: Default::default(),
-            overrideOS: Default::default(),
-            pid: Default::default(),
-            pidsLimit: Default::default(),
-            pod: None,
-            privileged: Default::default(),
-            publish: Default::default(),
-            publishAll: Default::default(),
-            pull: Default::default(),
-            quiet: Default::default(),
-            readonly: Default::default(),
-            readonlytmpfs: Default::default(),
-            restart: Default::default(),
-            rm: Default::default(),
-            rootfs: Default::default(),
-            securityOpt: Default::default(),
-            shmSize: Default::default(),
-            stopSignal: Default::default(),
-            stopTimeout: Default::default(),
-            storageOpt: Default::default(),
-            subuidname: Default::default(),
-            subgidname: Default::default(),
-            sysctl: Default::default(),
-            systemd: Default::default(),
-            tmpfs: Default::default(),
-            tty: Default::default(),
-            uidmap: Default::default(),
-            ulimit: Default::default(),
-            user: Default::default(),
-            userns: Default::default(),
-            uts: Default::default(),
-            mount: Default::default(),
-            volume: Default::default(),
-            volumesFrom: Default::default(),
-            workDir: Default::default(),
+        // The first argument is the image, the rest replace its command.
+        let mut args = vec![spec.image_name.0];
+        args.extend(spec.command.unwrap_or_default());
+
+        // Podman parses a JSON array as a list of arguments.
+        let entrypoint = match spec.entrypoint {
+            Some(entrypoint) => Some(serde_json::to_string(&entrypoint)?),
+            None => None,
+        };
+
+        // Podman has no separate domain name, but the hostname can be a
+        // fully qualified domain name.
+        let hostname = match (spec.hostname, spec.domainname) {
+            (Some(hostname), Some(domainname)) => Some(format!("{}.{}", hostname, domainname)),
+            (Some(hostname), None) => Some(hostname),
+            (None, Some(_)) => {
+                eprintln!(
+                    "WARNING: ignoring domainname of {}, it requires a hostname",
+                    spec.name.0
+                );
+                None
+            }
+            (None, None) => None,
         };
 
-        let reply = self.client.create_container(create_container).call()?;
-        let container = ContainerId(reply.container);
+        let create_container = CreateContainer::default()
+            .args(args)
+            .name(spec.name.0)
+            .label(labels)
+            .add_host(spec.extra_hosts)
+            .entrypoint(entrypoint)
+            .work_dir(spec.working_dir)
+            .user(spec.user)
+            .hostname(hostname)
+            .interactive(spec.stdin_open)
+            .tty(spec.tty)
+            .network(network)
+            .ip(ip_address)
+            .restart(restart_policy(spec.restart_policy))
+            .healthcheck_command(healthcheck_command)
+            .healthcheck_interval(healthcheck.and_then(|h| h.interval).map(go_duration))
+            .healthcheck_retries(healthcheck.and_then(|h| h.retries).map(|r| r as i64))
+            .healthcheck_start_period(healthcheck.and_then(|h| h.start_period).map(go_duration))
+            .healthcheck_timeout(healthcheck.and_then(|h| h.timeout).map(go_duration))
+            .cpu_period(resources.cpu_period.map(|p| p.as_micros() as i64))
+            .cpu_quota(resources.cpu_quota.map(|q| q.as_micros() as i64))
+            .cpu_shares(resources.cpu_shares.map(|s| s as i64))
+            .cpus(resources.nano_cpus.map(|c| c as f64 / 1e9))
+            .cpu_set_cpus(resources.cpuset)
+            .memory(resources.memory.map(|m| m.to_string()))
+            .memory_reservation(resources.memory_reservation.map(|m| m.to_string()))
+            .memory_swap(resources.memory_swap.map(|m| m.to_string()))
+            .memory_swappiness(resources.memory_swappiness)
+            .oom_score_adj(resources.oom_score_adj)
+            .pids_limit(resources.pids_limit)
+            .shm_size(resources.shm_size.map(|s| s.to_string()))
+            .cap_add(security.cap_add)
+            .cap_drop(security.cap_drop)
+            .privileged(security.privileged)
+            .readonly(security.read_only)
+            .security_opt(security.security_opt)
+            .userns(security.userns_mode)
+            .groupadd(security.group_add)
+            .device(security.devices)
+            .sysctl(security.sysctls)
+            .ulimit(security.ulimits)
+            .tmpfs(security.tmpfs)
+            .systemd(spec.podman.systemd)
+            .uidmap(spec.podman.uidmap)
+            .gidmap(spec.podman.gidmap)
+            .annotation(spec.podman.annotations);
+
+        let container = self.podman.create_container(create_container)?;
+
+        for (network, aliases) in network_aliases {
+            podman_command(&["network", "disconnect", &network.0, &container])?;
+
+            let mut args = vec!["network".to_owned(), "connect".to_owned()];
+            args.extend(aliases.iter().map(|alias| format!("--alias={}", alias)));
+            args.push(network.0);
+            args.push(container.clone());
+            podman_command(&args)?;
+        }
 
-        Ok(container)
+        Ok(ContainerId(container))
     }
 
     fn start_container(&mut self, name: &str) -> Result<ContainerId> {
-        let reply = self.client.start_container(name.to_owned()).call()?;
-        let container = ContainerId(reply.container);
+        let container = self.podman.start_container(name)?;
 
-        Ok(container)
+        Ok(ContainerId(container))
     }
 
     fn stop_container(&mut self, name: &str, timeout: u32) -> Result<ContainerId> {
-        let reply = self
-            .client
-            .stop_container(name.to_owned(), timeout as i64)
-            .call()?;
-        let container = ContainerId(reply.container);
+        let container = self.podman.stop_container(name, timeout as i64)?;
+
+        Ok(ContainerId(container))
+    }
+
+    fn unpause_container(&mut self, name: &str) -> Result<ContainerId> {
+        let container = self.podman.unpause_container(name)?;
+
+        Ok(ContainerId(container))
+    }
+
+    fn wait_container(&mut self, name: &str) -> Result<()> {
+        self.podman.wait_container(name, WAIT_INTERVAL_MS)?;
+
+        Ok(())
+    }
+
+    fn remove_container(
+        &mut self,
+        name: &str,
+        force: bool,
+        remove_volumes: bool,
+    ) -> Result<ContainerId> {
+        let container = self.podman.remove_container(name, force, remove_volumes)?;
+
+        Ok(ContainerId(container))
+    }
+
+    fn container_address(&mut self, name: &str) -> Result<Option<IpAddr>> {
+        let inspect = self.podman.inspect_container(name)?;
+        let inspect: serde_json::Value = serde_json::from_str(&inspect)?;
+
+        let network_settings = &inspect["NetworkSettings"];
+        let networks = network_settings["Networks"]
+            .as_object()
+            .into_iter()
+            .flat_map(|networks| networks.values());
+
+        let address = std::iter::once(network_settings)
+            .chain(networks)
+            .filter_map(|settings| settings["IPAddress"].as_str())
+            .find_map(|address| address.parse().ok());
+
+        Ok(address)
+    }
+
+    fn container_networks(&mut self, name: &str) -> Result<Vec<NetworkName>> {
+        let inspect = self.podman.inspect_container(name)?;
+        let inspect: serde_json::Value = serde_json::from_str(&inspect)?;
+
+        let networks = inspect["NetworkSettings"]["Networks"]
+            .as_object()
+            .into_iter()
+            .flat_map(|networks| networks.keys())
+            .map(|network| NetworkName(network.clone()))
+            .collect();
+
+        Ok(networks)
+    }
+
+    fn run_healthcheck(&mut self, name: &str) -> Result<HealthStatus> {
+        let status = self.podman.health_check_run(name)?;
+
+        match status.as_str() {
+            "healthy" => Ok(HealthStatus::Healthy),
+            "unhealthy" => Ok(HealthStatus::Unhealthy),
+            status => Err(anyhow!("unknown health status: {:?}", status).into()),
+        }
+    }
+
+    fn list_networks(&mut self, labels: Vec<(&str, &str)>) -> Result<Map<NetworkName, Network>> {
+        #[derive(Deserialize)]
+        struct NetworkListItem {
+            #[serde(alias = "Name")]
+            name: String,
+
+            #[serde(alias = "Labels", default)]
+            labels: Option<Map<String, String>>,
+        }
+
+        // Networks are not part of the varlink interface either.
+        let output = podman_command(&["network", "ls", "--format", "json"])?;
+        let networks: Vec<NetworkListItem> = serde_json::from_slice(&output)?;
+
+        let networks = networks
+            .into_iter()
+            .map(|network| Network {
+                name: NetworkName(network.name),
+                labels: network.labels.unwrap_or_default(),
+            })
+            .filter(|network| {
+                labels.iter().all(|(label, value)| {
+                    network.labels.get(*label).map(|s| s.as_str()) == Some(*value)
+                })
+            })
+            .map(|network| (network.name.clone(), network))
+            .collect();
+
+        Ok(networks)
+    }
+
+    fn create_network(&mut self, spec: &NetworkSpec) -> Result<()> {
+        let mut args = vec!["network".to_owned(), "create".to_owned()];
+
+        if let Some(ref driver) = spec.driver {
+            args.push(format!("--driver={}", driver));
+        }
+
+        if spec.internal {
+            args.push("--internal".into());
+        }
+
+        for subnet in spec.subnets.iter() {
+            args.push(format!("--subnet={}", subnet.subnet));
+
+            if let Some(ref gateway) = subnet.gateway {
+                args.push(format!("--gateway={}", gateway));
+            }
+
+            if let Some(ref ip_range) = subnet.ip_range {
+                args.push(format!("--ip-range={}", ip_range));
+            }
+        }
+
+        for (key, value) in spec.labels.iter() {
+            args.push(format!("--label={}={}", key, value));
+        }
+
+        args.push(spec.name.0.clone());
+
+        podman_command(&args)?;
 
-        Ok(container)
+        Ok(())
     }
 
-    fn remove_container(&mut self, name: &str, remove_volumes: bool) -> Result<ContainerId> {
-        let reply = self
-            .client
-            .remove_container(name.to_owned(), false, remove_volumes)
-            .call()?;
-        let container = ContainerId(reply.container);
+    fn remove_network(&mut self, name: &NetworkName) -> Result<()> {
+        podman_command(&["network", "rm", &name.0])?;
+
+        Ok(())
+    }
+
+    fn supports_network_aliases(&mut self, networks: &[NetworkName]) -> Result<bool> {
+        if !self.network_connect {
+            return Ok(false);
+        }
+
+        // Aliases are only resolved on networks with the dnsname plugin.
+        for network in networks {
+            let output = podman_command(&["network", "inspect", &network.0])?;
+            let inspect: serde_json::Value = serde_json::from_slice(&output)?;
+
+            let dnsname = inspect
+                .as_array()
+                .into_iter()
+                .flatten()
+                .flat_map(|config| config["plugins"].as_array().into_iter().flatten())
+                .any(|plugin| plugin["type"] == "dnsname");
+            if !dnsname {
+                return Ok(false);
+            }
+        }
 
-        Ok(container)
+        Ok(true)
     }
 }
@@ -1,13 +1,23 @@
-use anyhow::{anyhow, Result};
+use anyhow::anyhow;
 use blake3;
 use log::info;
-use std::collections::{BTreeMap as Map, BTreeSet as Set};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap as Map, BTreeSet as Set},
+    net::IpAddr,
+    path::Path,
+    slice, thread,
+    time::{Duration, Instant},
+};
 
 use crate::{
+    errors::{Error, Result},
     hasher::DigestHasher,
+    health::{self, HealthProbe},
     models::{
         BuildPolicy, Composition, Container, ContainerId, ContainerName, ContainerSpec,
-        ContainerStatus, PullPolicy,
+        ContainerStatus, HealthStatus, Healthcheck, ImageName, NetworkName, PullOptions,
+        PullPolicy, RestartPolicy, ServiceSpec, UpdateConfig, UpdateFailureAction, UpdateOrder,
     },
     services::ContainerBackend,
 };
@@ -15,6 +25,18 @@ use crate::{
 const LABEL_PROJECT: &str = "io.podman.compose.project";
 const LABEL_SERVICE: &str = "io.podman.compose.service";
 const LABEL_HASH: &str = "io.podman.compose.hash";
+const LABEL_HOSTS: &str = "io.podman.compose.hosts";
+const LABEL_REPLICA: &str = "io.podman.compose.replica";
+
+/// The labels of containers created by docker-compose, which podman-compose
+/// sets as well.
+const LABEL_COMPOSE_PROJECT: &str = "com.docker.compose.project";
+const LABEL_COMPOSE_SERVICE: &str = "com.docker.compose.service";
+const LABEL_COMPOSE_CONTAINER_NUMBER: &str = "com.docker.compose.container-number";
+const LABEL_COMPOSE_CONFIG_HASH: &str = "com.docker.compose.config-hash";
+const LABEL_COMPOSE_ONEOFF: &str = "com.docker.compose.oneoff";
+const LABEL_COMPOSE_WORKING_DIR: &str = "com.docker.compose.project.working_dir";
+const LABEL_COMPOSE_CONFIG_FILES: &str = "com.docker.compose.project.config_files";
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ContainerOperation {
@@ -25,11 +47,76 @@ pub enum ContainerOperation {
     Remove,
 }
 
+/// What a container operation applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContainerTarget {
+    /// A replica of a service, whether or not it has a container yet.
+    Replica { service_name: String, replica: u64 },
+    /// An existing container, whether or not it's still part of the
+    /// composition.
+    Container(ContainerId),
+}
+
+/// The result of a single operation passed to `Controller::apply`.
+#[derive(Debug)]
+pub enum OperationOutcome {
+    Done,
+    Failed(Error),
+    /// The operation was never attempted since the rolling update it was
+    /// part of was paused or rolled back.
+    Skipped,
+    /// The operation succeeded, but was undone when rolling back an update.
+    RolledBack,
+}
+
+/// The final status of a service reported by `Controller::wait_services`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ServiceStatus {
+    Running,
+    Healthy,
+    Unhealthy,
+    Exited,
+    TimedOut,
+}
+
+/// The hash of a container spec, which is stored on the container to find out
+/// whether it has to be recreated. Containers are identified by their labels,
+/// so their name isn't part of it.
+fn spec_hash(spec: &ContainerSpec) -> String {
+    let mut spec = spec.clone();
+    spec.name = ContainerName(String::new());
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.input(&spec);
+    hasher.finalize().to_hex().to_string()
+}
+
+/// A replica whose container has been replaced by
+/// `Controller::replace_batch`, the old container is kept stopped.
+struct Replaced {
+    old: Container,
+    was_running: bool,
+    new: Container,
+}
+
 pub struct Controller {
     backend: Box<dyn ContainerBackend>,
     composition: Composition,
-    containers: Map<ContainerName, Container>,
+    containers: Map<ContainerId, Container>,
     project_name: String,
+    /// Whether services are made reachable by writing their addresses to
+    /// `/etc/hosts`, when the networks of the project don't support aliases.
+    /// Decided by `create_networks` once the networks exist.
+    host_discovery: bool,
+    addresses: Map<ContainerId, Option<IpAddr>>,
+    /// The services that are operated on, or all of them if `None`.
+    selected_services: Option<Set<String>>,
+    /// Whether containers created by docker-compose or podman-compose are
+    /// recognized as part of the project.
+    label_compat: bool,
+    /// The docker-compose labels that are the same for all containers, if
+    /// they're written at all.
+    compose_labels: Option<Map<String, String>>,
 }
 
 impl Controller {
@@ -40,37 +127,282 @@ impl Controller {
     {
         let project_name = project_name.into();
         let mut backend = Box::new(backend);
-        let containers = backend.list_containers(vec![(LABEL_PROJECT, &project_name)])?;
+        let containers = backend
+            .list_containers(vec![(LABEL_PROJECT, &project_name)])?
+            .into_values()
+            .map(|container| (container.id.clone(), container))
+            .collect();
 
         Ok(Controller {
             backend,
             composition,
             containers,
             project_name,
+            host_discovery: false,
+            addresses: Map::new(),
+            selected_services: None,
+            label_compat: false,
+            compose_labels: None,
         })
     }
 
-    pub fn pull_images(&mut self, pull_policy: PullPolicy) -> Result<()> {
+    /// Also writes the labels of docker-compose on created containers, so
+    /// that tools made for docker-compose group them by project and service.
+    pub fn write_compose_labels(&mut self, working_dir: &Path, config_files: &[&Path]) {
+        let config_files = config_files
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>();
+
+        let mut labels = Map::new();
+        labels.insert(LABEL_COMPOSE_PROJECT.into(), self.project_name.clone());
+        labels.insert(LABEL_COMPOSE_ONEOFF.into(), "False".into());
+        labels.insert(
+            LABEL_COMPOSE_WORKING_DIR.into(),
+            working_dir.display().to_string(),
+        );
+        labels.insert(LABEL_COMPOSE_CONFIG_FILES.into(), config_files.join(","));
+
+        self.compose_labels = Some(labels);
+    }
+
+    /// Also recognizes the containers of the project that were created by
+    /// docker-compose or podman-compose, by their `com.docker.compose.*`
+    /// labels. They're treated like containers of an older version of the
+    /// compose file, and recreated when needed.
+    pub fn recognize_foreign_labels(&mut self) -> Result<()> {
+        let containers = self
+            .backend
+            .list_containers(vec![(LABEL_COMPOSE_PROJECT, &self.project_name)])?;
+        info!("found foreign containers: {:?}", containers.keys());
+
+        self.containers.extend(
+            containers
+                .into_values()
+                .map(|container| (container.id.clone(), container)),
+        );
+        self.label_compat = true;
+
+        Ok(())
+    }
+
+    /// The service of a container, from its labels.
+    fn container_service<'a>(&self, container: &'a Container) -> Option<&'a String> {
+        match container.labels.get(LABEL_SERVICE) {
+            Some(service) => Some(service),
+            None if self.label_compat => container.labels.get(LABEL_COMPOSE_SERVICE),
+            None => None,
+        }
+    }
+
+    /// The service and replica index of a container, from its labels.
+    /// Containers created before replicas were labelled are recognized by
+    /// the name they were given back then.
+    fn container_replica(&self, container: &Container) -> Option<(String, u64)> {
+        let service_name = self.container_service(container)?;
+
+        let replica = if let Some(replica) = container.labels.get(LABEL_REPLICA) {
+            replica.parse().ok()?
+        } else if let (true, Some(number)) = (
+            self.label_compat,
+            container.labels.get(LABEL_COMPOSE_CONTAINER_NUMBER),
+        ) {
+            // docker-compose numbers replicas from 1.
+            number.parse::<u64>().ok()?.checked_sub(1)?
+        } else {
+            let prefix = format!("{}_{}_", self.project_name, service_name);
+            container.name.0.strip_prefix(&prefix)?.parse().ok()?
+        };
+
+        Some((service_name.clone(), replica))
+    }
+
+    /// The existing container of a replica. If there's more than one, like
+    /// when an update was interrupted, containers created by pod-compose are
+    /// preferred over foreign ones, and running ones over the others.
+    fn replica_container(&self, spec: &ContainerSpec) -> Option<&Container> {
+        self.containers
+            .values()
+            .filter(|container| match self.container_replica(container) {
+                Some((service_name, replica)) => {
+                    service_name == spec.service_name && replica == spec.replica
+                }
+                None => false,
+            })
+            .max_by_key(|container| {
+                (
+                    container.labels.contains_key(LABEL_HASH),
+                    container.status == ContainerStatus::Running,
+                )
+            })
+    }
+
+    /// The name of the container an operation applies to, for display.
+    pub fn target_name(&self, target: &ContainerTarget) -> String {
+        match target {
+            ContainerTarget::Replica {
+                service_name,
+                replica,
+            } => match self.replica_spec(service_name, *replica) {
+                Ok(spec) => spec.name.0,
+                Err(_) => format!("{} #{}", service_name, replica),
+            },
+            ContainerTarget::Container(id) => match self.containers.get(id) {
+                Some(container) => container.name.0.clone(),
+                None => id.0.clone(),
+            },
+        }
+    }
+
+    /// Limits all following operations to some of the services, and the
+    /// services they depend on if `with_dependencies` is set. Other services
+    /// are left as they are. All services stay selected if none are given.
+    pub fn select_services(
+        &mut self,
+        service_names: &[String],
+        with_dependencies: bool,
+    ) -> Result<()> {
+        if service_names.is_empty() {
+            return Ok(());
+        }
+
+        let mut selected = Set::new();
+        let mut pending = service_names.to_vec();
+
+        while let Some(service_name) = pending.pop() {
+            if !self.composition.services.contains_key(&service_name) {
+                return Err(Error::NotFound {
+                    kind: "service",
+                    name: service_name,
+                });
+            }
+
+            if with_dependencies && !selected.contains(&service_name) {
+                pending.extend(self.service_dependencies(&service_name));
+            }
+            selected.insert(service_name);
+        }
+
+        info!("selected services: {:?}", selected);
+        self.selected_services = Some(selected);
+
+        Ok(())
+    }
+
+    fn is_selected(&self, service_name: &str) -> bool {
+        match self.selected_services {
+            Some(ref selected) => selected.contains(service_name),
+            None => true,
+        }
+    }
+
+    /// Whether an image is used by any of the selected services.
+    fn is_image_selected(&self, image_name: &ImageName) -> bool {
+        self.composition
+            .containers
+            .iter()
+            .any(|spec| spec.image_name == *image_name && self.is_selected(&spec.service_name))
+    }
+
+    /// The settings of the first selected service that uses an image.
+    fn image_service(&self, image_name: &ImageName) -> Option<&ServiceSpec> {
+        self.composition
+            .containers
+            .iter()
+            .find(|spec| spec.image_name == *image_name && self.is_selected(&spec.service_name))
+            .and_then(|spec| self.composition.services.get(&spec.service_name))
+    }
+
+    /// The pull policy of an image: the given one if any, otherwise the one
+    /// of its service.
+    fn image_pull_policy(
+        &self,
+        image_name: &ImageName,
+        pull_policy: Option<PullPolicy>,
+    ) -> PullPolicy {
+        pull_policy
+            .or_else(|| self.image_service(image_name)?.pull_policy)
+            .unwrap_or(PullPolicy::IfNotPresent)
+    }
+
+    /// Pulls the images of the selected services, with the given policy or
+    /// else the policies of their services.
+    pub fn pull_images(
+        &mut self,
+        pull_policy: Option<PullPolicy>,
+        options: PullOptions,
+    ) -> Result<()> {
         for image_spec in self.composition.pull_images.iter() {
+            if !self.is_image_selected(&image_spec.name) {
+                continue;
+            }
+
+            let pull_policy = self.image_pull_policy(&image_spec.name, pull_policy);
             let image = self.backend.get_image(&image_spec.name)?;
 
-            match (pull_policy, image) {
-                (PullPolicy::IfNotPresent, None) | (PullPolicy::Always, _) => {
-                    self.backend.pull_image(&image_spec.name)?;
+            let pulled = match (pull_policy, image) {
+                (PullPolicy::IfNotPresent, None) | (PullPolicy::Always, _) => self
+                    .backend
+                    .pull_image(&image_spec.name, options.quiet)
+                    .map(|_| ()),
+                (PullPolicy::Never, None) => Err(Error::NotFound {
+                    kind: "image",
+                    name: image_spec.name.0.clone(),
+                }),
+                _ => Ok(()),
+            };
+
+            match pulled {
+                Err(err) if options.ignore_failures => {
+                    eprintln!("WARNING: could not pull {}: {}", image_spec.name.0, err)
                 }
-                _ => (),
+                pulled => pulled?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pushes the images of the selected services that are both built and
+    /// named by the compose file, printing their progress unless `quiet` is
+    /// set.
+    pub fn push_images(&mut self, quiet: bool) -> Result<()> {
+        for image_name in self.composition.push_images.iter() {
+            if !self.is_image_selected(image_name) {
+                continue;
             }
+
+            info!("pushing image {}", image_name.0);
+            self.backend
+                .push_image(image_name, quiet)
+                .map_err(|err| err.context(format!("could not push {}", image_name.0)))?;
         }
 
         Ok(())
     }
 
+    /// Builds the images of the selected services. Services can ask to always
+    /// be built, and the pull policy of base images is the given one or else
+    /// the one of their service.
     pub fn build_images(
         &mut self,
         build_policy: BuildPolicy,
-        pull_policy: PullPolicy,
+        pull_policy: Option<PullPolicy>,
     ) -> Result<()> {
         for image_spec in self.composition.build_images.iter() {
+            if !self.is_image_selected(&image_spec.name) {
+                continue;
+            }
+
+            let build_policy = match build_policy {
+                BuildPolicy::IfChanged => self
+                    .image_service(&image_spec.name)
+                    .and_then(|service| service.build_policy)
+                    .unwrap_or(build_policy),
+                build_policy => build_policy,
+            };
+            let pull_policy = self.image_pull_policy(&image_spec.name, pull_policy);
+
             let mut hasher = blake3::Hasher::new();
             hasher.input(&image_spec);
             let spec_hash = hasher.finalize();
@@ -105,27 +437,138 @@ impl Controller {
         Ok(())
     }
 
+    /// Creates the networks of the composition that don't exist yet and
+    /// returns their names. External networks are only required to exist.
+    pub fn create_networks(&mut self) -> Result<Vec<NetworkName>> {
+        let existing_networks = self.backend.list_networks(vec![])?;
+        let mut created_networks = Vec::new();
+
+        for network_spec in self.composition.networks.iter() {
+            if network_spec.external {
+                if !existing_networks.contains_key(&network_spec.name) {
+                    return Err(Error::NotFound {
+                        kind: "external network",
+                        name: network_spec.name.0.clone(),
+                    });
+                }
+                continue;
+            }
+
+            let mut hasher = blake3::Hasher::new();
+            hasher.input(network_spec);
+            let spec_hash = hasher.finalize();
+            let spec_hash = spec_hash.to_hex();
+
+            if let Some(network) = existing_networks.get(&network_spec.name) {
+                // Networks can't be changed, and recreating them would mean
+                // recreating every container connected to them.
+                if network.labels.get(LABEL_HASH).map(|h| h.as_str()) != Some(spec_hash.as_str()) {
+                    eprintln!(
+                        "WARNING: network {} has changed, run down to recreate it.",
+                        network_spec.name.0
+                    );
+                }
+                continue;
+            }
+
+            let mut network_spec = network_spec.clone();
+            network_spec
+                .labels
+                .insert(LABEL_PROJECT.into(), self.project_name.clone());
+            network_spec
+                .labels
+                .insert(LABEL_HASH.into(), spec_hash.to_string());
+
+            self.backend.create_network(&network_spec)?;
+            created_networks.push(network_spec.name);
+        }
+
+        let network_names = self
+            .composition
+            .networks
+            .iter()
+            .map(|network_spec| network_spec.name.clone())
+            .collect::<Vec<_>>();
+        self.host_discovery = !self.backend.supports_network_aliases(&network_names)?;
+
+        if self.host_discovery {
+            info!("network aliases are not supported, using host entries instead");
+        }
+
+        Ok(created_networks)
+    }
+
+    /// Removes all networks that were created for this project. Networks
+    /// are kept when only some services are selected, since the others may
+    /// still be connected to them, and so are networks that other
+    /// containers are still attached to.
+    pub fn remove_networks(&mut self) -> Result<Vec<NetworkName>> {
+        if self.selected_services.is_some() {
+            return Ok(Vec::new());
+        }
+
+        let networks = self
+            .backend
+            .list_networks(vec![(LABEL_PROJECT, &self.project_name)])?;
+
+        // Containers that were left behind, like orphans, keep their
+        // networks from being removed.
+        let mut attached = Map::<NetworkName, Vec<ContainerName>>::new();
+        for container in self.backend.list_containers(vec![])?.into_values() {
+            for network_name in self.backend.container_networks(&container.id.0)? {
+                attached
+                    .entry(network_name)
+                    .or_default()
+                    .push(container.name.clone());
+            }
+        }
+
+        let mut removed = Vec::new();
+        for network_name in networks.into_keys() {
+            if let Some(containers) = attached.get(&network_name) {
+                let containers = containers
+                    .iter()
+                    .map(|name| name.0.as_str())
+                    .collect::<Vec<_>>();
+                eprintln!(
+                    "WARNING: network {} was not removed, containers are still attached to it: {}",
+                    network_name.0,
+                    containers.join(", ")
+                );
+                continue;
+            }
+
+            self.backend.remove_network(&network_name)?;
+            removed.push(network_name);
+        }
+
+        Ok(removed)
+    }
+
     /// Finds containers with a project label that is the same as the current project.
     /// This is useful in situations where the user removes a service from the
     /// compose file but forgets to stop and remove the container.
-    pub fn find_orphans(&mut self) -> Result<Vec<ContainerName>> {
+    pub fn find_orphans(&mut self) -> Result<Vec<ContainerId>> {
+        // Services that are disabled by their profiles are still part of the
+        // project.
         let services = self
             .composition
             .containers
             .iter()
             .map(|spec| spec.service_name.clone())
+            .chain(self.composition.disabled_services.iter().cloned())
             .collect::<Set<_>>();
 
         info!("found services: {:?}", services);
 
         let orphans = self
             .containers
-            .iter()
-            .filter_map(|(container_name, container)| {
-                let service = container.labels.get(LABEL_SERVICE);
+            .values()
+            .filter_map(|container| {
+                let service = self.container_service(container);
                 match service {
                     Some(service) if services.contains(service) => None,
-                    _ => Some(container_name.clone()),
+                    _ => Some(container.id.clone()),
                 }
             })
             .collect();
@@ -135,179 +578,1644 @@ impl Controller {
         Ok(orphans)
     }
 
-    pub fn start_containers_diff(&mut self) -> Result<Vec<(ContainerName, ContainerOperation)>> {
-        let diff = self.composition.containers.iter().filter_map(|spec| {
-            let mut hasher = blake3::Hasher::new();
-            hasher.input(&spec);
-            let spec_hash = hasher.finalize();
+    pub fn start_containers_diff(&mut self) -> Result<Vec<(ContainerTarget, ContainerOperation)>> {
+        // Services are started after the services they depend on.
+        let container_specs = self.ordered_container_specs()?;
+
+        // Services scaled to zero replicas don't have any container specs.
+        let services = self
+            .composition
+            .services
+            .keys()
+            .filter(|service_name| self.is_selected(service_name))
+            .cloned()
+            .collect::<Set<_>>();
+
+        let mut current = Set::new();
+        let mut diff = Vec::new();
 
-            let container = match self.containers.get(&spec.name) {
+        for spec in container_specs.iter() {
+            let container = match self.replica_container(spec) {
                 Some(container) => container,
-                None => return Some((spec.name.clone(), ContainerOperation::Create)),
+                None => {
+                    let target = ContainerTarget::Replica {
+                        service_name: spec.service_name.clone(),
+                        replica: spec.replica,
+                    };
+                    diff.push((target, ContainerOperation::Create));
+                    continue;
+                }
             };
 
-            let container_hash = container.labels.get(LABEL_HASH);
-            if container_hash
-                .map(|h| h == spec_hash.to_hex().as_str())
-                .unwrap_or(false)
-            {
+            current.insert(container.id.clone());
+            let target = ContainerTarget::Container(container.id.clone());
+
+            if container.labels.get(LABEL_HASH) == Some(&spec_hash(spec)) {
                 let operation = match container.status {
                     ContainerStatus::Configured => Some(ContainerOperation::Start),
                     ContainerStatus::Running => None,
+                    // Podman brings it back up by itself.
+                    ContainerStatus::Restarting => None,
+                    ContainerStatus::Paused => Some(ContainerOperation::Start),
+                    ContainerStatus::Stopping => Some(ContainerOperation::Start),
                     ContainerStatus::Exited => Some(ContainerOperation::Start),
                     ContainerStatus::Unknown => Some(ContainerOperation::Recreate),
                 };
 
-                operation.map(|operation| (spec.name.clone(), operation))
+                diff.extend(operation.map(|operation| (target, operation)));
             } else {
-                Some((spec.name.clone(), ContainerOperation::Recreate))
+                diff.push((target, ContainerOperation::Recreate));
             }
-        });
+        }
 
-        let services = self
-            .composition
+        // If the user scales down any service, we need to find the old
+        // containers and remove them, along with any other containers of
+        // the service that no replica uses. Making sure we don't also remove
+        // orphans. Replicas are removed starting from the highest index.
+        let mut scaled_down_containers = self
             .containers
-            .iter()
-            .map(|spec| spec.service_name.clone())
-            .collect::<Set<_>>();
+            .values()
+            .filter(|container| !current.contains(&container.id))
+            .filter(|container| match self.container_service(container) {
+                Some(service) => services.contains(service),
+                None => false,
+            })
+            .map(|container| {
+                let replica = self
+                    .container_replica(container)
+                    .map(|(_, replica)| replica);
+                (replica, container.id.clone())
+            })
+            .collect::<Vec<_>>();
 
-        // If the user scales down any service, we need to find the old
-        // containers and remove them. Making sure we don't also remove
-        // orphans.
-        let scaled_down_containers =
-            self.containers
+        scaled_down_containers.sort_by_key(|(replica, _)| Reverse(*replica));
+
+        let diff = scaled_down_containers
+            .into_iter()
+            .map(|(_, id)| (ContainerTarget::Container(id), ContainerOperation::Remove))
+            .chain(diff)
+            .collect();
+
+        Ok(diff)
+    }
+
+    /// When services are discovered through host entries, finds the
+    /// containers that have to be recreated since the addresses of the
+    /// services they depend on have changed.
+    pub fn host_entries_diff(&mut self) -> Result<Vec<(ContainerTarget, ContainerOperation)>> {
+        if !self.host_discovery {
+            return Ok(Vec::new());
+        }
+
+        let mut recreated_services = Set::new();
+        let mut diff = Vec::new();
+
+        for spec in self.ordered_container_specs()? {
+            let container = match self.replica_container(&spec) {
+                Some(container) => container.clone(),
+                None => continue,
+            };
+
+            // Recreating a container changes its address, so everything that
+            // depends on it has to be recreated as well.
+            let dependencies = self.service_dependencies(&spec.service_name);
+            let mut recreate = dependencies
                 .iter()
-                .filter_map(|(container_name, container)| {
-                    let container_should_exist = self
-                        .composition
-                        .containers
-                        .iter()
-                        .find(|container_spec| container_spec.name == *container_name)
-                        .is_some();
-
-                    let service = container.labels.get(LABEL_SERVICE);
-                    match service {
-                        Some(service) if services.contains(service) && !container_should_exist => {
-                            Some((container_name.clone(), ContainerOperation::Remove))
-                        }
-                        _ => None,
-                    }
-                });
+                .any(|dependency| recreated_services.contains(dependency));
 
-        let diff = diff.chain(scaled_down_containers).collect();
+            if !recreate {
+                let (_, hosts_hash) = self.discovered_hosts(&spec)?;
+                recreate = container.labels.get(LABEL_HOSTS) != Some(&hosts_hash);
+            }
+
+            if recreate {
+                recreated_services.insert(spec.service_name.clone());
+                diff.push((
+                    ContainerTarget::Container(container.id),
+                    ContainerOperation::Recreate,
+                ));
+            }
+        }
 
         Ok(diff)
     }
 
-    pub fn stop_containers_diff(&mut self) -> Result<Vec<(ContainerName, ContainerOperation)>> {
-        let diff = self
-            .composition
-            .containers
-            .iter()
-            .filter_map(|spec| match self.containers.get(&spec.name) {
-                Some(container) if container.status == ContainerStatus::Running => {
-                    Some((spec.name.clone(), ContainerOperation::Stop))
+    /// The existing containers of the selected services, including replicas
+    /// that aren't part of the composition anymore, like the ones added by
+    /// `up --scale`, and the containers of services that have since been
+    /// disabled by their profiles. Orphans are left out.
+    fn service_containers(&self) -> Vec<&Container> {
+        self.containers
+            .values()
+            .filter(|container| match self.container_service(container) {
+                Some(service) => {
+                    (self.composition.services.contains_key(service)
+                        || self.composition.disabled_services.contains(service))
+                        && self.is_selected(service)
                 }
-                _ => None,
+                None => false,
+            })
+            .collect()
+    }
+
+    pub fn stop_containers_diff(&mut self) -> Result<Vec<(ContainerTarget, ContainerOperation)>> {
+        let diff = self
+            .service_containers()
+            .into_iter()
+            .filter(|container| container.status == ContainerStatus::Running)
+            .map(|container| {
+                (
+                    ContainerTarget::Container(container.id.clone()),
+                    ContainerOperation::Stop,
+                )
             })
             .collect();
 
         Ok(diff)
     }
 
-    pub fn remove_containers_diff(&mut self) -> Result<Vec<(ContainerName, ContainerOperation)>> {
+    pub fn remove_containers_diff(&mut self) -> Result<Vec<(ContainerTarget, ContainerOperation)>> {
         let diff = self
-            .composition
-            .containers
-            .iter()
-            .filter_map(|spec| match self.containers.get(&spec.name) {
-                Some(_container) => Some((spec.name.clone(), ContainerOperation::Remove)),
-                _ => None,
+            .service_containers()
+            .into_iter()
+            .map(|container| {
+                (
+                    ContainerTarget::Container(container.id.clone()),
+                    ContainerOperation::Remove,
+                )
             })
             .collect();
 
         Ok(diff)
     }
 
-    pub fn container_apply(
+    /// Finds the operations that replace the containers created by
+    /// docker-compose or podman-compose with containers owned by
+    /// pod-compose. Containers that are labelled as a replica are
+    /// recreated, others are removed and the missing replicas of their
+    /// services are created. Containers of unknown services are left alone.
+    pub fn adopt_containers_diff(&mut self) -> Result<Vec<(ContainerTarget, ContainerOperation)>> {
+        // Containers that pod-compose created always have a hash.
+        let is_foreign = |container: &Container| !container.labels.contains_key(LABEL_HASH);
+
+        let mut adopted_services = Set::new();
+
+        for container in self.containers.values() {
+            let service = match self.container_service(container) {
+                Some(service) if is_foreign(container) => service,
+                _ => continue,
+            };
+
+            if !self.composition.services.contains_key(service) || !self.is_selected(service) {
+                continue;
+            }
+            adopted_services.insert(service.clone());
+        }
+
+        let mut current = Set::new();
+        let mut replaced = Vec::new();
+
+        for spec in self.ordered_container_specs()? {
+            if !adopted_services.contains(&spec.service_name) {
+                continue;
+            }
+
+            match self.replica_container(&spec) {
+                Some(container) => {
+                    current.insert(container.id.clone());
+                    if is_foreign(container) {
+                        replaced.push((
+                            ContainerTarget::Container(container.id.clone()),
+                            ContainerOperation::Recreate,
+                        ));
+                    }
+                }
+                None => replaced.push((
+                    ContainerTarget::Replica {
+                        service_name: spec.service_name.clone(),
+                        replica: spec.replica,
+                    },
+                    ContainerOperation::Create,
+                )),
+            }
+        }
+
+        let removed = self
+            .containers
+            .values()
+            .filter(|container| is_foreign(container) && !current.contains(&container.id))
+            .filter(|container| match self.container_service(container) {
+                Some(service) => adopted_services.contains(service),
+                None => false,
+            })
+            .map(|container| {
+                (
+                    ContainerTarget::Container(container.id.clone()),
+                    ContainerOperation::Remove,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        Ok(removed.into_iter().chain(replaced).collect())
+    }
+
+    /// Applies a list of operations, calling `report` with the index and
+    /// outcome of each operation as it finishes.
+    ///
+    /// A failing operation doesn't stop the others from being applied,
+    /// except for the operations that bring up services depending on the
+    /// service that failed, which are skipped. Recreating the replicas of a
+    /// service is done as a rolling update, following the service's update
+    /// config.
+    pub fn apply<F>(
         &mut self,
-        name: &ContainerName,
-        operation: ContainerOperation,
+        operations: &[(ContainerTarget, ContainerOperation)],
         timeout: u32,
-    ) -> Result<()> {
-        let container_spec = || -> Result<ContainerSpec> {
-            self.composition
-                .containers
+        mut report: F,
+    ) -> Result<()>
+    where
+        F: FnMut(usize, OperationOutcome) -> Result<()>,
+    {
+        let mut rolled_out = Set::new();
+        // Services with a failed operation, and the services that were
+        // skipped because of them.
+        let mut failed_services = Set::new();
+
+        for (index, (target, operation)) in operations.iter().enumerate() {
+            let service_name = self.operation_service(target);
+
+            let brings_up =
+                *operation != ContainerOperation::Stop && *operation != ContainerOperation::Remove;
+            if let (true, Some(service_name)) = (brings_up, &service_name) {
+                let depends_on_failed = self
+                    .service_dependencies(service_name)
+                    .iter()
+                    .any(|dependency| failed_services.contains(dependency));
+                if depends_on_failed {
+                    failed_services.insert(service_name.clone());
+                    report(index, OperationOutcome::Skipped)?;
+                    continue;
+                }
+            }
+
+            if *operation != ContainerOperation::Recreate {
+                let outcome = match self.container_apply(target, *operation, timeout) {
+                    Ok(()) => OperationOutcome::Done,
+                    Err(err) => {
+                        failed_services.extend(service_name);
+                        OperationOutcome::Failed(err)
+                    }
+                };
+                report(index, outcome)?;
+                continue;
+            }
+
+            let service_name = match service_name {
+                Some(service_name) => service_name,
+                None => {
+                    let err = anyhow!("{} is not part of any service", self.target_name(target));
+                    report(index, OperationOutcome::Failed(err.into()))?;
+                    continue;
+                }
+            };
+
+            if !rolled_out.insert(service_name.clone()) {
+                continue;
+            }
+
+            // All replicas of a service are recreated together, as soon as
+            // the first one shows up in the list of operations.
+            let replicas = operations
                 .iter()
-                .find(|spec| spec.name == *name)
-                .ok_or_else(|| anyhow!("unknown container name: {:?}", name))
-                .map(|spec| spec.clone())
+                .enumerate()
+                .skip(index)
+                .filter(|(_, (target, operation))| {
+                    *operation == ContainerOperation::Recreate
+                        && self.operation_service(target).as_ref() == Some(&service_name)
+                })
+                .map(|(index, (target, _))| (index, target.clone()))
+                .collect::<Vec<_>>();
+
+            if self.rolling_update(&service_name, replicas, timeout, &mut report)? {
+                failed_services.insert(service_name);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The service of the container an operation applies to, from the
+    /// labels of existing containers.
+    fn operation_service(&self, target: &ContainerTarget) -> Option<String> {
+        match target {
+            ContainerTarget::Replica { service_name, .. } => Some(service_name.clone()),
+            ContainerTarget::Container(id) => self
+                .containers
+                .get(id)
+                .and_then(|container| self.container_service(container))
+                .cloned(),
+        }
+    }
+
+    /// Recreates `replicas` of a service in batches. Replicas within a batch
+    /// are updated at the same time, in the order of the update config, and
+    /// the batch is checked for failures before the delay. The
+    /// replaced containers are kept, stopped, until all batches are done so
+    /// that the update can be rolled back. Returns whether any replica
+    /// failed to update.
+    fn rolling_update<F>(
+        &mut self,
+        service_name: &str,
+        replicas: Vec<(usize, ContainerTarget)>,
+        timeout: u32,
+        report: &mut F,
+    ) -> Result<bool>
+    where
+        F: FnMut(usize, OperationOutcome) -> Result<()>,
+    {
+        let update_config = self.update_config(service_name);
+
+        let batch_size = match update_config.parallelism {
+            0 => replicas.len().max(1),
+            parallelism => parallelism as usize,
         };
 
-        match operation {
-            ContainerOperation::Create => {
-                let container_spec = container_spec()?;
-                let container_id = self.container_create(container_spec)?;
-                self.backend.start_container(&container_id.0)?;
+        let mut updated = Vec::new();
+        let mut batches = replicas.chunks(batch_size).enumerate();
+        let mut any_failed = false;
+
+        while let Some((batch_index, batch)) = batches.next() {
+            if batch_index > 0 && update_config.delay > Duration::from_secs(0) {
+                thread::sleep(update_config.delay);
             }
-            ContainerOperation::Recreate => {
-                let container_spec = container_spec()?;
-                let container = self
-                    .containers
-                    .get(name)
-                    .ok_or_else(|| anyhow!("could not find container {:?}", name))?;
 
-                if container.status == ContainerStatus::Running {
-                    self.backend.stop_container(&container.id.0, timeout)?;
+            let mut failed = false;
+
+            let targets = batch
+                .iter()
+                .map(|(_, target)| target.clone())
+                .collect::<Vec<_>>();
+            let results = self.replace_batch(&targets, update_config.order, timeout);
+
+            for ((index, _), result) in batch.iter().zip(results) {
+                match result {
+                    Ok(replaced) => {
+                        updated.push((*index, replaced));
+                        report(*index, OperationOutcome::Done)?;
+                    }
+                    Err(err) => {
+                        failed = true;
+                        report(*index, OperationOutcome::Failed(err))?;
+                    }
                 }
-                self.backend.remove_container(&container.id.0, false)?;
-                let container_id = self.container_create(container_spec)?;
-                self.backend.start_container(&container_id.0)?;
             }
-            ContainerOperation::Start => {
-                let container = self
-                    .containers
-                    .get(name)
-                    .ok_or_else(|| anyhow!("could not find container {:?}", name))?;
-                self.backend.start_container(&container.id.0)?;
+
+            any_failed |= failed;
+            if !failed || update_config.failure_action == UpdateFailureAction::Continue {
+                continue;
             }
-            ContainerOperation::Stop => {
-                let container = self
-                    .containers
-                    .get(name)
-                    .ok_or_else(|| anyhow!("could not find container {:?}", name))?;
-                self.backend.stop_container(&container.id.0, timeout)?;
+
+            if update_config.failure_action == UpdateFailureAction::Rollback {
+                for (index, replaced) in updated.drain(..).rev() {
+                    let outcome = match self.replaced_restore(replaced, timeout) {
+                        Ok(()) => OperationOutcome::RolledBack,
+                        Err(err) => OperationOutcome::Failed(err),
+                    };
+                    report(index, outcome)?;
+                }
             }
-            ContainerOperation::Remove => {
-                let container = self
-                    .containers
-                    .get(name)
-                    .ok_or_else(|| anyhow!("could not find container {:?}", name))?;
 
-                if container.status == ContainerStatus::Running {
-                    self.backend.stop_container(&container.id.0, timeout)?;
+            for (_, batch) in batches.by_ref() {
+                for (index, _) in batch.iter() {
+                    report(*index, OperationOutcome::Skipped)?;
                 }
-                self.backend.remove_container(&container.id.0, false)?;
             }
         }
 
-        Ok(())
+        for (index, replaced) in updated {
+            if let Err(err) = self.replaced_remove(replaced) {
+                report(index, OperationOutcome::Failed(err))?;
+            }
+        }
+
+        Ok(any_failed)
     }
 
-    pub fn container_create(&mut self, mut spec: ContainerSpec) -> Result<ContainerId> {
-        let mut hasher = blake3::Hasher::new();
-        hasher.input(&spec);
-        let hash = hasher.finalize();
+    /// The names of the selected services.
+    pub fn service_names(&self) -> Vec<String> {
+        self.composition
+            .services
+            .keys()
+            .filter(|service_name| self.is_selected(service_name))
+            .cloned()
+            .collect()
+    }
+
+    /// Waits until the containers of every service are running, or healthy
+    /// if they have a healthcheck. `report` is called for every service as
+    /// soon as its final status is known.
+    pub fn wait_services<F>(&mut self, timeout: Option<Duration>, mut report: F) -> Result<()>
+    where
+        F: FnMut(&str, ServiceStatus) -> Result<()>,
+    {
+        let started = Instant::now();
+
+        let mut pending = Map::new();
+        for spec in self.composition.containers.iter() {
+            if !self.is_selected(&spec.service_name) {
+                continue;
+            }
+
+            pending
+                .entry(spec.service_name.clone())
+                .or_insert_with(Vec::new)
+                .push((spec.clone(), HealthProbe::new()));
+        }
+
+        while !pending.is_empty() {
+            let mut finished = Vec::new();
+
+            for (service_name, replicas) in pending.iter_mut() {
+                if let Some(status) = self.service_status(replicas)? {
+                    finished.push((service_name.clone(), status));
+                }
+            }
+
+            for (service_name, status) in finished {
+                pending.remove(&service_name);
+                report(&service_name, status)?;
+            }
+
+            if pending.is_empty() {
+                break;
+            }
+
+            if timeout.map(|t| started.elapsed() >= t).unwrap_or(false) {
+                for service_name in pending.keys() {
+                    report(service_name, ServiceStatus::TimedOut)?;
+                }
+                break;
+            }
+
+            thread::sleep(health::POLL_INTERVAL);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the replicas of a service once, returns `None` if they're
+    /// still starting.
+    fn service_status(
+        &mut self,
+        replicas: &mut [(ContainerSpec, HealthProbe)],
+    ) -> Result<Option<ServiceStatus>> {
+        let mut has_healthcheck = false;
+        let mut starting = false;
+
+        for (spec, probe) in replicas.iter_mut() {
+            let id = match self.replica_container(spec) {
+                Some(container) => container.id.clone(),
+                None => return Ok(Some(ServiceStatus::Exited)),
+            };
+            let container = self.backend.get_container(&id.0)?;
+            // Podman will restart containers that should always be running,
+            // so an exited container is just in between restarts.
+            let restarts = match spec.restart_policy {
+                RestartPolicy::Always | RestartPolicy::UnlessStopped => true,
+                RestartPolicy::No | RestartPolicy::OnFailure { .. } => false,
+            };
+
+            match container.map(|container| container.status) {
+                Some(ContainerStatus::Running) => (),
+                Some(ContainerStatus::Configured) | Some(ContainerStatus::Restarting) => {
+                    starting = true;
+                    continue;
+                }
+                Some(ContainerStatus::Exited) if restarts => {
+                    starting = true;
+                    continue;
+                }
+                _ => return Ok(Some(ServiceStatus::Exited)),
+            }
+
+            let healthcheck = match spec.healthcheck {
+                Some(ref healthcheck) if healthcheck.is_enabled() => healthcheck,
+                _ => continue,
+            };
+
+            has_healthcheck = true;
+
+            let status = self.backend.run_healthcheck(&id.0)?;
+            if status != HealthStatus::Healthy {
+                if probe.record(healthcheck, status) {
+                    return Ok(Some(ServiceStatus::Unhealthy));
+                }
+                starting = true;
+            }
+        }
+
+        let status = match (starting, has_healthcheck) {
+            (true, _) => None,
+            (false, true) => Some(ServiceStatus::Healthy),
+            (false, false) => Some(ServiceStatus::Running),
+        };
+
+        Ok(status)
+    }
+
+    pub fn container_apply(
+        &mut self,
+        target: &ContainerTarget,
+        operation: ContainerOperation,
+        timeout: u32,
+    ) -> Result<()> {
+        match operation {
+            ContainerOperation::Create => {
+                let container_spec = self.target_spec(target)?;
+                let hash = spec_hash(&container_spec);
+                let mut container = self.container_create(container_spec, &hash)?;
+                self.backend.start_container(&container.id.0)?;
+
+                container.status = ContainerStatus::Running;
+                self.containers.insert(container.id.clone(), container);
+            }
+            ContainerOperation::Recreate => {
+                self.container_recreate(target, timeout)?;
+            }
+            ContainerOperation::Start => {
+                let mut container = self.target_container(target)?;
+
+                // Paused containers are resumed, and stopping ones can only
+                // be started again once they have exited.
+                let started = match container.status {
+                    ContainerStatus::Paused => self.backend.unpause_container(&container.id.0),
+                    ContainerStatus::Stopping => self
+                        .backend
+                        .wait_container(&container.id.0)
+                        .and_then(|()| self.backend.start_container(&container.id.0)),
+                    _ => self.backend.start_container(&container.id.0),
+                };
+
+                if let Err(err) = started {
+                    // Containers with a restart policy might have been
+                    // restarted by podman since we listed them. If that
+                    // can't be checked, the error of the start is reported.
+                    let restarted = matches!(
+                        self.backend.get_container(&container.id.0),
+                        Ok(Some(current)) if current.status == ContainerStatus::Running
+                    );
+                    if !restarted {
+                        return Err(err);
+                    }
+                }
+                container.status = ContainerStatus::Running;
+                self.addresses.remove(&container.id);
+                self.containers.insert(container.id.clone(), container);
+            }
+            ContainerOperation::Stop => {
+                let mut container = self.target_container(target)?;
+                self.backend.stop_container(&container.id.0, timeout)?;
+                container.status = ContainerStatus::Exited;
+                self.addresses.remove(&container.id);
+                self.containers.insert(container.id.clone(), container);
+            }
+            ContainerOperation::Remove => {
+                let container = self.target_container(target)?;
+
+                if container.status == ContainerStatus::Running {
+                    self.backend.stop_container(&container.id.0, timeout)?;
+                }
+                self.backend
+                    .remove_container(&container.id.0, false, false)?;
+                self.containers.remove(&container.id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The existing container an operation applies to.
+    fn target_container(&self, target: &ContainerTarget) -> Result<Container> {
+        let container = match target {
+            ContainerTarget::Replica {
+                service_name,
+                replica,
+            } => {
+                let spec = self.replica_spec(service_name, *replica)?;
+                self.replica_container(&spec)
+            }
+            ContainerTarget::Container(id) => self.containers.get(id),
+        };
+
+        container.cloned().ok_or_else(|| Error::NotFound {
+            kind: "container",
+            name: self.target_name(target),
+        })
+    }
+
+    /// The spec of the replica an operation applies to, existing containers
+    /// are matched to their replica by their labels.
+    fn target_spec(&self, target: &ContainerTarget) -> Result<ContainerSpec> {
+        match target {
+            ContainerTarget::Replica {
+                service_name,
+                replica,
+            } => self.replica_spec(service_name, *replica),
+            ContainerTarget::Container(id) => {
+                let container = self.containers.get(id).ok_or_else(|| Error::NotFound {
+                    kind: "container",
+                    name: id.0.clone(),
+                })?;
+                let (service_name, replica) = self
+                    .container_replica(container)
+                    .ok_or_else(|| anyhow!("{} is not a replica of a service", container.name.0))?;
+                self.replica_spec(&service_name, replica)
+            }
+        }
+    }
+
+    fn replica_spec(&self, service_name: &str, replica: u64) -> Result<ContainerSpec> {
+        self.composition
+            .containers
+            .iter()
+            .find(|spec| spec.service_name == service_name && spec.replica == replica)
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown replica {} of {}", replica, service_name).into())
+    }
+
+    fn service_dependencies(&self, service_name: &str) -> Set<String> {
+        self.composition
+            .services
+            .get(service_name)
+            .map(|service| service.dependencies())
+            .unwrap_or_default()
+    }
+
+    /// Orders the services so that every service comes after the services it
+    /// depends on.
+    fn service_order(&self) -> Result<Vec<String>> {
+        fn visit(
+            services: &Map<String, ServiceSpec>,
+            service_name: &str,
+            visiting: &mut Vec<String>,
+            order: &mut Vec<String>,
+        ) -> Result<()> {
+            if order.iter().any(|name| name == service_name) {
+                return Ok(());
+            }
+
+            if visiting.iter().any(|name| name == service_name) {
+                visiting.push(service_name.into());
+                return Err(anyhow!("circular dependency: {}", visiting.join(" -> ")).into());
+            }
+
+            let service = services
+                .get(service_name)
+                .ok_or_else(|| anyhow!("unknown service: {:?}", service_name))?;
+
+            visiting.push(service_name.into());
+            for dependency in service.dependencies() {
+                visit(services, &dependency, visiting, order).map_err(|err| {
+                    err.context(format!("in the dependencies of {}", service_name))
+                })?;
+            }
+            visiting.pop();
+
+            order.push(service_name.into());
+
+            Ok(())
+        }
+
+        let mut order = Vec::new();
+        for service_name in self.composition.services.keys() {
+            visit(
+                &self.composition.services,
+                service_name,
+                &mut Vec::new(),
+                &mut order,
+            )?;
+        }
+
+        Ok(order)
+    }
+
+    /// The container specs of the selected services, ordered by
+    /// `service_order`.
+    fn ordered_container_specs(&self) -> Result<Vec<ContainerSpec>> {
+        let service_order = self.service_order()?;
+
+        let mut container_specs = self
+            .composition
+            .containers
+            .iter()
+            .filter(|spec| self.is_selected(&spec.service_name))
+            .cloned()
+            .collect::<Vec<_>>();
+        container_specs.sort_by_key(|spec| {
+            service_order
+                .iter()
+                .position(|service_name| *service_name == spec.service_name)
+        });
+
+        Ok(container_specs)
+    }
+
+    fn container_address(&mut self, id: &ContainerId) -> Result<Option<IpAddr>> {
+        if let Some(address) = self.addresses.get(id) {
+            return Ok(*address);
+        }
+
+        let address = self.backend.container_address(&id.0)?;
+        self.addresses.insert(id.clone(), address);
+
+        Ok(address)
+    }
+
+    /// Returns the `/etc/hosts` entries that make all other running
+    /// containers reachable from a container, by their container names,
+    /// service names, links and network aliases. Also returns a hash of the
+    /// entries for the services the container depends on.
+    fn discovered_hosts(&mut self, spec: &ContainerSpec) -> Result<(Vec<String>, String)> {
+        let dependencies = self.service_dependencies(&spec.service_name);
+        let links = self
+            .composition
+            .services
+            .get(&spec.service_name)
+            .map(|service| service.links.clone())
+            .unwrap_or_default();
+
+        let mut hosts = Vec::new();
+        let mut dependency_hosts = Vec::new();
+        let mut seen_services = Set::new();
+
+        for other_spec in self.composition.containers.clone() {
+            if other_spec.service_name == spec.service_name && other_spec.replica == spec.replica {
+                continue;
+            }
+
+            let container = match self.replica_container(&other_spec) {
+                Some(container) if container.status == ContainerStatus::Running => {
+                    container.clone()
+                }
+                _ => continue,
+            };
+
+            let address = match self.container_address(&container.id)? {
+                Some(address) => address,
+                None => continue,
+            };
+
+            let mut names = vec![other_spec.name.0.clone()];
+
+            // The service name and links point to the first replica.
+            if seen_services.insert(other_spec.service_name.clone()) {
+                names.push(other_spec.service_name.clone());
+                names.extend(
+                    links
+                        .iter()
+                        .filter(|(_, service_name)| **service_name == other_spec.service_name)
+                        .map(|(alias, _)| alias.clone()),
+                );
+            }
+
+            for attachment in other_spec.networks.values() {
+                names.extend(attachment.aliases.iter().cloned());
+            }
+
+            for name in names {
+                let host = format!("{}:{}", name, address);
+                if dependencies.contains(&other_spec.service_name) {
+                    dependency_hosts.push(host.clone());
+                }
+                hosts.push(host);
+            }
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.input(&dependency_hosts);
+        let hosts_hash = hasher.finalize().to_hex().to_string();
+
+        Ok((hosts, hosts_hash))
+    }
+
+    /// Returns the names that make the containers of a service reachable
+    /// when networks support aliases: the service name and the links to it.
+    fn service_aliases(&self, service_name: &str) -> Vec<String> {
+        let links = self
+            .composition
+            .services
+            .values()
+            .flat_map(|service| service.links.iter())
+            .filter(|(_, linked_service)| *linked_service == service_name)
+            .map(|(alias, _)| alias.clone());
+
+        let mut aliases = vec![service_name.to_owned()];
+        for alias in links {
+            if !aliases.contains(&alias) {
+                aliases.push(alias);
+            }
+        }
+
+        aliases
+    }
+
+    fn update_config(&self, service_name: &str) -> UpdateConfig {
+        self.composition
+            .services
+            .get(service_name)
+            .map(|service| service.update_config.clone())
+            .unwrap_or_default()
+    }
+
+    /// Replaces the container of a replica with a new one created from its
+    /// spec, and removes the old container.
+    fn container_recreate(&mut self, target: &ContainerTarget, timeout: u32) -> Result<()> {
+        let spec = self.target_spec(target)?;
+        let order = self.update_config(&spec.service_name).order;
+
+        let replaced = self
+            .replace_batch(slice::from_ref(target), order, timeout)
+            .remove(0)?;
+        self.replaced_remove(replaced)
+    }
+
+    /// Replaces the containers of a batch of replicas with new ones created
+    /// from their specs, all at the same time. Start-first, the new
+    /// containers are started, and have to be healthy, before the old
+    /// containers are stopped, so that the replicas stay up. Stop-first, the
+    /// old containers are stopped before, for replicas that can't run twice.
+    ///
+    /// The old containers are kept until `replaced_remove` or
+    /// `replaced_restore`. If a new container fails, it's removed and its
+    /// old container is left running, or started again, as it was.
+    fn replace_batch(
+        &mut self,
+        targets: &[ContainerTarget],
+        order: UpdateOrder,
+        timeout: u32,
+    ) -> Vec<Result<Replaced>> {
+        let mut results = Vec::new();
+        let mut pending = Vec::new();
+
+        for (index, target) in targets.iter().enumerate() {
+            match self.replace_start(target, order, timeout) {
+                Ok((replaced, healthcheck)) => {
+                    pending.push((index, replaced, healthcheck, HealthProbe::new()))
+                }
+                Err(err) => results.push((index, Err(err))),
+            }
+        }
+
+        // The new containers of the batch become healthy side by side.
+        while !pending.is_empty() {
+            let mut starting = Vec::new();
+
+            for (index, replaced, healthcheck, mut probe) in pending {
+                let healthy = match healthcheck {
+                    Some(ref healthcheck) => {
+                        self.container_healthy(&replaced.new, healthcheck, &mut probe)
+                    }
+                    None => Ok(true),
+                };
+
+                match healthy {
+                    Ok(true) => results.push((index, self.replace_finish(replaced, timeout))),
+                    Ok(false) => starting.push((index, replaced, healthcheck, probe)),
+                    Err(err) => {
+                        results.push((index, Err(self.replace_abort(replaced, err, timeout))))
+                    }
+                }
+            }
+
+            pending = starting;
+            if !pending.is_empty() {
+                thread::sleep(health::POLL_INTERVAL);
+            }
+        }
+
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Creates and starts the new container of a replica, after stopping the
+    /// old one if it's replaced stop-first. Returns the healthcheck the new
+    /// container has to pass.
+    fn replace_start(
+        &mut self,
+        target: &ContainerTarget,
+        order: UpdateOrder,
+        timeout: u32,
+    ) -> Result<(Replaced, Option<Healthcheck>)> {
+        let spec = self.target_spec(target)?;
+        let mut old = self.target_container(target)?;
+        let was_running = old.status == ContainerStatus::Running;
+
+        if order == UpdateOrder::StopFirst && was_running {
+            self.backend
+                .stop_container(&old.id.0, timeout)
+                .map_err(|err| err.context(format!("could not stop {}", old.name.0)))?;
+            old.status = ContainerStatus::Exited;
+            self.addresses.remove(&old.id);
+            self.containers.insert(old.id.clone(), old.clone());
+        }
+
+        let healthcheck = spec.healthcheck.clone().filter(|h| h.is_enabled());
+        let hash = spec_hash(&spec);
+
+        let new = match self.container_create(spec, &hash) {
+            Ok(new) => new,
+            Err(err) => {
+                let err = err.context(format!("could not replace {}", old.name.0));
+                return match self.replaced_resume(old, was_running) {
+                    Ok(()) => Err(err),
+                    Err(resume_err) => {
+                        Err(anyhow!("{}; rolling back also failed: {}", err, resume_err).into())
+                    }
+                };
+            }
+        };
+        self.containers.insert(new.id.clone(), new.clone());
+
+        let mut replaced = Replaced {
+            old,
+            was_running,
+            new,
+        };
+
+        if let Err(err) = self.backend.start_container(&replaced.new.id.0) {
+            return Err(self.replace_abort(replaced, err, timeout));
+        }
+        replaced.new.status = ContainerStatus::Running;
+        self.containers
+            .insert(replaced.new.id.clone(), replaced.new.clone());
+
+        Ok((replaced, healthcheck))
+    }
+
+    /// Stops the old container of a replica once its new container is
+    /// healthy, if it's still running.
+    fn replace_finish(&mut self, mut replaced: Replaced, timeout: u32) -> Result<Replaced> {
+        if replaced.old.status == ContainerStatus::Running {
+            let old = &replaced.old;
+            if let Err(err) = self.backend.stop_container(&old.id.0, timeout) {
+                let err = err.context(format!("could not stop {}", old.name.0));
+                return Err(self.replace_abort(replaced, err, timeout));
+            }
+
+            replaced.old.status = ContainerStatus::Exited;
+            self.addresses.remove(&replaced.old.id);
+            self.containers
+                .insert(replaced.old.id.clone(), replaced.old.clone());
+        }
+
+        Ok(replaced)
+    }
+
+    /// Undoes a replacement that failed, returning the error it failed with.
+    fn replace_abort(&mut self, replaced: Replaced, err: Error, timeout: u32) -> Error {
+        let err = err.context(format!("could not replace {}", replaced.old.name.0));
+
+        match self.replaced_restore(replaced, timeout) {
+            Ok(()) => err,
+            Err(restore_err) => {
+                anyhow!("{}; rolling back also failed: {}", err, restore_err).into()
+            }
+        }
+    }
+
+    /// Runs the healthcheck of a new container once, returns whether it's
+    /// healthy yet, or an error once it's unhealthy.
+    fn container_healthy(
+        &mut self,
+        container: &Container,
+        healthcheck: &Healthcheck,
+        probe: &mut HealthProbe,
+    ) -> Result<bool> {
+        let status = self.backend.run_healthcheck(&container.id.0)?;
+        if status == HealthStatus::Healthy {
+            return Ok(true);
+        }
+
+        if probe.record(healthcheck, status) {
+            return Err(anyhow!("{} is unhealthy", container.name.0).into());
+        }
+
+        Ok(false)
+    }
+
+    /// Removes the old container of a replica that was replaced.
+    fn replaced_remove(&mut self, replaced: Replaced) -> Result<()> {
+        let old = replaced.old;
+        self.backend
+            .remove_container(&old.id.0, false, false)
+            .map_err(|err| err.context(format!("could not remove {}", old.name.0)))?;
+        self.containers.remove(&old.id);
+
+        Ok(())
+    }
+
+    /// Undoes a replacement by removing the new container and starting the
+    /// old one again if it was running.
+    fn replaced_restore(&mut self, replaced: Replaced, timeout: u32) -> Result<()> {
+        self.container_discard(&replaced.new, timeout)?;
+        self.replaced_resume(replaced.old, replaced.was_running)
+    }
+
+    /// Starts the old container of a replica again if it was running before
+    /// it was stopped to be replaced.
+    fn replaced_resume(&mut self, mut old: Container, was_running: bool) -> Result<()> {
+        if was_running && old.status != ContainerStatus::Running {
+            self.backend.start_container(&old.id.0)?;
+            old.status = ContainerStatus::Running;
+            self.containers.insert(old.id.clone(), old);
+        }
+
+        Ok(())
+    }
+
+    /// Removes a container that was created by pod-compose, whether or not
+    /// it's running.
+    fn container_discard(&mut self, container: &Container, timeout: u32) -> Result<()> {
+        // It may never have started or may have exited already, so stopping
+        // it is allowed to fail, removing it by force isn't.
+        let _ = self.backend.stop_container(&container.id.0, timeout);
+        self.backend
+            .remove_container(&container.id.0, true, false)?;
+        self.containers.remove(&container.id);
+        self.addresses.remove(&container.id);
+
+        Ok(())
+    }
+
+    /// Creates a container from its spec, labelled with the `hash` of the
+    /// spec as it's compared by `start_containers_diff`. Podman can't rename
+    /// containers, so if another container of the project has the name of
+    /// the spec, like the container being replaced, the name is followed by
+    /// a part of the hash.
+    pub fn container_create(&mut self, mut spec: ContainerSpec, hash: &str) -> Result<Container> {
+        let name_in_use = |name: &ContainerName| {
+            self.containers
+                .values()
+                .any(|container| container.name == *name)
+        };
+        if name_in_use(&spec.name) {
+            spec.name = ContainerName(format!("{}_{}", spec.name.0, &hash[..12]));
+        }
 
         spec.labels
             .insert(LABEL_PROJECT.into(), self.project_name.clone());
         spec.labels
             .insert(LABEL_SERVICE.into(), spec.service_name.clone());
         spec.labels
-            .insert(LABEL_HASH.into(), hash.to_hex().to_string());
+            .insert(LABEL_REPLICA.into(), spec.replica.to_string());
+        spec.labels.insert(LABEL_HASH.into(), hash.to_owned());
 
+        // Not part of the hash either, so that they can be turned on and off
+        // without recreating containers.
+        if let Some(ref compose_labels) = self.compose_labels {
+            spec.labels.extend(compose_labels.clone());
+            spec.labels
+                .insert(LABEL_COMPOSE_SERVICE.into(), spec.service_name.clone());
+            // docker-compose numbers replicas from 1.
+            spec.labels.insert(
+                LABEL_COMPOSE_CONTAINER_NUMBER.into(),
+                (spec.replica + 1).to_string(),
+            );
+            spec.labels
+                .insert(LABEL_COMPOSE_CONFIG_HASH.into(), hash.to_owned());
+        }
+
+        // Discovered hosts are not part of the hash, they are compared
+        // separately by `host_entries_diff`.
+        if self.host_discovery {
+            let (hosts, hosts_hash) = self.discovered_hosts(&spec)?;
+            spec.extra_hosts.extend(hosts);
+            spec.labels.insert(LABEL_HOSTS.into(), hosts_hash);
+        } else {
+            let aliases = self.service_aliases(&spec.service_name);
+            for attachment in spec.networks.values_mut() {
+                attachment.aliases.extend(aliases.iter().cloned());
+            }
+        }
+
+        let name = spec.name.clone();
+        let labels = spec.labels.clone();
         let id = self.backend.create_container(spec)?;
 
-        Ok(id)
+        Ok(Container {
+            id,
+            name,
+            status: ContainerStatus::Configured,
+            labels,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Image, ImageBuildSpec, ImageId, Network, NetworkSpec, PullPolicy};
+    use std::{cell::RefCell, rc::Rc};
+
+    #[derive(Default)]
+    struct State {
+        containers: Vec<Container>,
+        created: u64,
+        /// What happened to which container, like `start proj_web_0`.
+        events: Vec<String>,
+        /// Makes starting the containers with names that start with it fail.
+        failing: Option<String>,
+        /// Makes looking up containers fail.
+        failing_lookups: bool,
+    }
+
+    /// Keeps containers in memory. Clones share them, so that tests can look
+    /// at them after giving the backend to a controller.
+    #[derive(Clone, Default)]
+    struct FakeBackend(Rc<RefCell<State>>);
+
+    impl FakeBackend {
+        fn names(&self) -> Set<String> {
+            let state = self.0.borrow();
+            state.containers.iter().map(|c| c.name.0.clone()).collect()
+        }
+
+        fn ids(&self) -> Set<ContainerId> {
+            let state = self.0.borrow();
+            state.containers.iter().map(|c| c.id.clone()).collect()
+        }
+
+        fn add(&self, name: &str, labels: &[(&str, &str)]) {
+            let mut state = self.0.borrow_mut();
+            state.created += 1;
+            let container = Container {
+                id: ContainerId(format!("id{}", state.created)),
+                name: ContainerName(name.into()),
+                status: ContainerStatus::Running,
+                labels: labels
+                    .iter()
+                    .map(|(label, value)| (label.to_string(), value.to_string()))
+                    .collect(),
+            };
+            state.containers.push(container);
+        }
+
+        fn events(&self) -> Vec<String> {
+            self.0.borrow_mut().events.drain(..).collect()
+        }
+
+        fn update<F>(&mut self, event: &str, name: &str, update: F) -> Result<ContainerId>
+        where
+            F: FnOnce(&mut Vec<Container>, usize) -> Result<()>,
+        {
+            let mut state = self.0.borrow_mut();
+            let index = state
+                .containers
+                .iter()
+                .position(|c| c.id.0 == name || c.name.0 == name)
+                .ok_or_else(|| Error::NotFound {
+                    kind: "container",
+                    name: name.into(),
+                })?;
+            let id = state.containers[index].id.clone();
+            let event = format!("{} {}", event, state.containers[index].name.0);
+            update(&mut state.containers, index)?;
+            state.events.push(event);
+            Ok(id)
+        }
+
+        fn set_status(
+            &mut self,
+            event: &str,
+            name: &str,
+            status: ContainerStatus,
+        ) -> Result<ContainerId> {
+            self.update(event, name, |containers, index| {
+                containers[index].status = status;
+                Ok(())
+            })
+        }
+    }
+
+    impl ContainerBackend for FakeBackend {
+        fn get_image(&mut self, _name: &ImageName) -> Result<Option<Image>> {
+            Ok(None)
+        }
+
+        fn pull_image(&mut self, name: &ImageName, _quiet: bool) -> Result<ImageId> {
+            Ok(ImageId(name.0.clone()))
+        }
+
+        fn push_image(&mut self, _name: &ImageName, _quiet: bool) -> Result<()> {
+            Ok(())
+        }
+
+        fn build_image(&mut self, spec: &ImageBuildSpec, _: PullPolicy) -> Result<ImageId> {
+            Ok(ImageId(spec.name.0.clone()))
+        }
+
+        fn list_containers(
+            &mut self,
+            labels: Vec<(&str, &str)>,
+        ) -> Result<Map<ContainerName, Container>> {
+            let state = self.0.borrow();
+            let containers = state
+                .containers
+                .iter()
+                .filter(|container| {
+                    labels.iter().all(|(label, value)| {
+                        container.labels.get(*label).map(|v| v.as_str()) == Some(*value)
+                    })
+                })
+                .map(|container| (container.name.clone(), container.clone()))
+                .collect();
+            Ok(containers)
+        }
+
+        fn get_container(&mut self, name: &str) -> Result<Option<Container>> {
+            let state = self.0.borrow();
+            if state.failing_lookups {
+                return Err(anyhow!("could not look up {}", name).into());
+            }
+            let container = state
+                .containers
+                .iter()
+                .find(|c| c.id.0 == name || c.name.0 == name);
+            Ok(container.cloned())
+        }
+
+        fn create_container(&mut self, spec: ContainerSpec) -> Result<ContainerId> {
+            if self.names().contains(&spec.name.0) {
+                return Err(Error::Conflict(format!("{} is in use", spec.name.0)));
+            }
+
+            let labels = spec.labels.iter();
+            let labels = labels
+                .map(|(label, value)| (label.as_str(), value.as_str()))
+                .collect::<Vec<_>>();
+            self.add(&spec.name.0, &labels);
+
+            self.set_status("create", &spec.name.0, ContainerStatus::Configured)
+        }
+
+        fn start_container(&mut self, name: &str) -> Result<ContainerId> {
+            let state = self.0.borrow();
+            let container = state
+                .containers
+                .iter()
+                .find(|c| c.id.0 == name || c.name.0 == name);
+            if let (Some(failing), Some(container)) = (&state.failing, container) {
+                if container.name.0.starts_with(failing) {
+                    return Err(anyhow!("could not start {}", name).into());
+                }
+            }
+            drop(state);
+            self.set_status("start", name, ContainerStatus::Running)
+        }
+
+        fn stop_container(&mut self, name: &str, _timeout: u32) -> Result<ContainerId> {
+            self.set_status("stop", name, ContainerStatus::Exited)
+        }
+
+        fn unpause_container(&mut self, name: &str) -> Result<ContainerId> {
+            self.set_status("unpause", name, ContainerStatus::Running)
+        }
+
+        fn wait_container(&mut self, name: &str) -> Result<()> {
+            self.set_status("wait", name, ContainerStatus::Exited)
+                .map(|_| ())
+        }
+
+        fn remove_container(
+            &mut self,
+            name: &str,
+            force: bool,
+            _remove_volumes: bool,
+        ) -> Result<ContainerId> {
+            self.update("remove", name, |containers, index| {
+                if containers[index].status == ContainerStatus::Running && !force {
+                    return Err(Error::Conflict("the container is running".into()));
+                }
+                containers.remove(index);
+                Ok(())
+            })
+        }
+
+        fn container_address(&mut self, _name: &str) -> Result<Option<IpAddr>> {
+            Ok(None)
+        }
+
+        fn container_networks(&mut self, _name: &str) -> Result<Vec<NetworkName>> {
+            Ok(Vec::new())
+        }
+
+        fn run_healthcheck(&mut self, _name: &str) -> Result<HealthStatus> {
+            Ok(HealthStatus::Healthy)
+        }
+
+        fn list_networks(&mut self, _: Vec<(&str, &str)>) -> Result<Map<NetworkName, Network>> {
+            Ok(Map::new())
+        }
+
+        fn create_network(&mut self, _spec: &NetworkSpec) -> Result<()> {
+            Ok(())
+        }
+
+        fn remove_network(&mut self, _name: &NetworkName) -> Result<()> {
+            Ok(())
+        }
+
+        fn supports_network_aliases(&mut self, _networks: &[NetworkName]) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    fn container_spec(service_name: &str, replica: u64, name: String) -> ContainerSpec {
+        ContainerSpec {
+            name: ContainerName(name),
+            service_name: service_name.into(),
+            replica,
+            image_name: ImageName("docker.io/library/nginx".into()),
+            labels: Map::new(),
+            command: None,
+            entrypoint: None,
+            working_dir: None,
+            user: None,
+            hostname: None,
+            domainname: None,
+            tty: false,
+            stdin_open: false,
+            resources: Default::default(),
+            security: Default::default(),
+            podman: Default::default(),
+            healthcheck: None,
+            restart_policy: RestartPolicy::No,
+            networks: Map::new(),
+            extra_hosts: Vec::new(),
+        }
+    }
+
+    /// A composition with a single service `web`, its replicas are named by
+    /// `name`.
+    fn composition<F>(replicas: u64, name: F) -> Composition
+    where
+        F: Fn(u64) -> String,
+    {
+        let mut composition = Composition::default();
+        composition
+            .services
+            .insert("web".into(), ServiceSpec::default());
+        composition.containers = (0..replicas)
+            .map(|replica| container_spec("web", replica, name(replica)))
+            .collect();
+        composition
+    }
+
+    /// Applies the operations and returns the last outcome of each.
+    fn apply(
+        controller: &mut Controller,
+        operations: &[(ContainerTarget, ContainerOperation)],
+    ) -> Vec<OperationOutcome> {
+        let mut outcomes = operations
+            .iter()
+            .map(|_| OperationOutcome::Skipped)
+            .collect::<Vec<_>>();
+        controller
+            .apply(operations, 0, |index, outcome| {
+                outcomes[index] = outcome;
+                Ok(())
+            })
+            .unwrap();
+        outcomes
+    }
+
+    fn up(backend: &FakeBackend, composition: Composition) -> Controller {
+        let mut controller = Controller::init("proj", backend.clone(), composition).unwrap();
+        let diff = controller.start_containers_diff().unwrap();
+        for outcome in apply(&mut controller, &diff) {
+            assert!(matches!(outcome, OperationOutcome::Done), "{:?}", outcome);
+        }
+        backend.events();
+        controller
+    }
+
+    /// The composition with the command of `web` changed.
+    fn changed(mut composition: Composition) -> Composition {
+        for spec in composition.containers.iter_mut() {
+            spec.command = Some(vec!["true".into()]);
+        }
+        composition
+    }
+
+    #[test]
+    fn renamed_replicas_are_left_alone() {
+        let backend = FakeBackend::default();
+        up(&backend, composition(2, |i| format!("proj_web_{}", i)));
+        let ids = backend.ids();
+
+        let renamed = composition(2, |i| format!("proj-web-{}", i + 1));
+        let mut controller = Controller::init("proj", backend.clone(), renamed).unwrap();
+
+        assert!(controller.find_orphans().unwrap().is_empty());
+        assert!(controller.start_containers_diff().unwrap().is_empty());
+        assert_eq!(backend.ids(), ids);
+    }
+
+    #[test]
+    fn replicas_are_matched_by_their_labels() {
+        let backend = FakeBackend::default();
+        up(&backend, composition(2, |i| format!("proj_web_{}", i)));
+
+        // The names of the replicas are swapped, nothing has to change.
+        let swapped = composition(2, |i| format!("proj_web_{}", 1 - i));
+        let mut controller = Controller::init("proj", backend.clone(), swapped).unwrap();
+
+        assert!(controller.start_containers_diff().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replicas_are_recognized_by_their_old_names() {
+        let backend = FakeBackend::default();
+        let labels = [(LABEL_PROJECT, "proj"), (LABEL_SERVICE, "web")];
+        backend.add("proj_web_0", &labels);
+        backend.add("proj_web_1", &labels);
+
+        let composition = composition(2, |i| format!("proj_web_{}", i));
+        let mut controller = Controller::init("proj", backend.clone(), composition).unwrap();
+        let diff = controller.start_containers_diff().unwrap();
+
+        let operations = diff.iter().map(|(_, operation)| *operation);
+        let operations = operations.collect::<Vec<_>>();
+        assert_eq!(operations, vec![ContainerOperation::Recreate; 2]);
+    }
+
+    #[test]
+    fn replacements_start_before_the_old_containers_stop() {
+        let backend = FakeBackend::default();
+        let composition = composition(1, |i| format!("proj_web_{}", i));
+        up(&backend, composition.clone());
+
+        let mut controller =
+            Controller::init("proj", backend.clone(), changed(composition.clone())).unwrap();
+        let diff = controller.start_containers_diff().unwrap();
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff[0].1, ContainerOperation::Recreate);
+
+        let outcomes = apply(&mut controller, &diff);
+        assert!(matches!(outcomes[0], OperationOutcome::Done));
+
+        let names = backend.names().into_iter().collect::<Vec<_>>();
+        assert_eq!(names.len(), 1);
+        let new_name = &names[0];
+        assert!(new_name.starts_with("proj_web_0_"));
+        assert_eq!(
+            backend.events(),
+            vec![
+                format!("create {}", new_name),
+                format!("start {}", new_name),
+                "stop proj_web_0".to_string(),
+                "remove proj_web_0".to_string(),
+            ]
+        );
+
+        // The next replacement gets the name of the spec again.
+        let mut controller = Controller::init("proj", backend.clone(), composition).unwrap();
+        let diff = controller.start_containers_diff().unwrap();
+        apply(&mut controller, &diff);
+        let names = backend.names().into_iter().collect::<Vec<_>>();
+        assert_eq!(names, vec!["proj_web_0".to_string()]);
+    }
+
+    #[test]
+    fn failed_replacements_leave_the_old_containers_alone() {
+        let backend = FakeBackend::default();
+        let composition = composition(1, |i| format!("proj_web_{}", i));
+        up(&backend, composition.clone());
+        let ids = backend.ids();
+
+        backend.0.borrow_mut().failing = Some("proj_web_0_".into());
+        let mut controller =
+            Controller::init("proj", backend.clone(), changed(composition)).unwrap();
+        let diff = controller.start_containers_diff().unwrap();
+
+        let outcomes = apply(&mut controller, &diff);
+        assert!(matches!(outcomes[0], OperationOutcome::Failed(_)));
+
+        assert_eq!(backend.ids(), ids);
+        let events = backend.events();
+        assert!(events.iter().all(|event| !event.ends_with(" proj_web_0")));
+        let state = backend.0.borrow();
+        assert_eq!(state.containers[0].status, ContainerStatus::Running);
+    }
+
+    #[test]
+    fn failed_starts_are_reported_when_the_container_cant_be_looked_up() {
+        let mut backend = FakeBackend::default();
+        up(&backend, composition(1, |i| format!("proj_web_{}", i)));
+        backend
+            .set_status("stop", "proj_web_0", ContainerStatus::Exited)
+            .unwrap();
+
+        backend.0.borrow_mut().failing = Some("proj_web_0".into());
+        backend.0.borrow_mut().failing_lookups = true;
+        let composition = composition(1, |i| format!("proj_web_{}", i));
+        let mut controller = Controller::init("proj", backend.clone(), composition).unwrap();
+        let diff = controller.start_containers_diff().unwrap();
+
+        let outcomes = apply(&mut controller, &diff);
+        match &outcomes[0] {
+            OperationOutcome::Failed(err) => assert!(err.to_string().contains("could not start")),
+            outcome => panic!("{:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn rolled_back_replicas_get_their_old_containers_back() {
+        let backend = FakeBackend::default();
+        let mut composition = composition(2, |i| format!("proj_web_{}", i));
+        let service = composition.services.get_mut("web").unwrap();
+        service.update_config.failure_action = UpdateFailureAction::Rollback;
+        up(&backend, composition.clone());
+        let ids = backend.ids();
+
+        backend.0.borrow_mut().failing = Some("proj_web_1_".into());
+        let mut controller =
+            Controller::init("proj", backend.clone(), changed(composition)).unwrap();
+        let diff = controller.start_containers_diff().unwrap();
+
+        let outcomes = apply(&mut controller, &diff);
+        assert!(matches!(outcomes[0], OperationOutcome::RolledBack));
+        assert!(matches!(outcomes[1], OperationOutcome::Failed(_)));
+
+        assert_eq!(backend.ids(), ids);
+        let state = backend.0.borrow();
+        assert!(state
+            .containers
+            .iter()
+            .all(|container| container.status == ContainerStatus::Running));
+    }
+
+    #[test]
+    fn stop_first_replacements_stop_the_old_containers_first() {
+        let backend = FakeBackend::default();
+        let mut composition = composition(1, |i| format!("proj_web_{}", i));
+        let service = composition.services.get_mut("web").unwrap();
+        service.update_config.order = UpdateOrder::StopFirst;
+        up(&backend, composition.clone());
+
+        let mut controller =
+            Controller::init("proj", backend.clone(), changed(composition)).unwrap();
+        let diff = controller.start_containers_diff().unwrap();
+        apply(&mut controller, &diff);
+
+        let events = backend.events();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], "stop proj_web_0");
+        assert!(events[1].starts_with("create proj_web_0_"));
+        assert!(events[2].starts_with("start proj_web_0_"));
+        assert_eq!(events[3], "remove proj_web_0");
+    }
+
+    #[test]
+    fn replicas_of_a_batch_are_replaced_together() {
+        let backend = FakeBackend::default();
+        let mut composition = composition(2, |i| format!("proj_web_{}", i));
+        let service = composition.services.get_mut("web").unwrap();
+        service.update_config.parallelism = 2;
+        up(&backend, composition.clone());
+
+        let mut controller =
+            Controller::init("proj", backend.clone(), changed(composition)).unwrap();
+        let diff = controller.start_containers_diff().unwrap();
+        apply(&mut controller, &diff);
+
+        // Both replacements are started before any old container is stopped.
+        let events = backend.events();
+        let verbs = events
+            .iter()
+            .map(|event| event.split(' ').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            verbs,
+            vec!["create", "start", "create", "start", "stop", "stop", "remove", "remove"]
+        );
+    }
+
+    #[test]
+    fn down_removes_replicas_added_by_scale() {
+        let backend = FakeBackend::default();
+        up(&backend, composition(3, |i| format!("proj_web_{}", i)));
+
+        let composition = composition(1, |i| format!("proj_web_{}", i));
+        let mut controller = Controller::init("proj", backend.clone(), composition).unwrap();
+        assert!(controller.find_orphans().unwrap().is_empty());
+
+        let diff = controller.stop_containers_diff().unwrap();
+        assert_eq!(diff.len(), 3);
+
+        let diff = controller.remove_containers_diff().unwrap();
+        assert_eq!(diff.len(), 3);
+        apply(&mut controller, &diff);
+        assert!(backend.names().is_empty());
     }
 }
@@ -0,0 +1,133 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use podman_varlink::PodmanError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The errors of the frontends, backends and the controller. Errors that
+/// don't fit any of the specific kinds are kept as `Other`.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{}: {message}", location(.file, *.line, *.column))]
+    Parse {
+        file: PathBuf,
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+
+    #[error("could not connect to podman: {0:#}")]
+    Connection(anyhow::Error),
+
+    #[error("{kind} {name:?} could not be found")]
+    NotFound { kind: &'static str, name: String },
+
+    #[error("{0}")]
+    Conflict(String),
+
+    #[error("could not build {image}: {reason}")]
+    BuildFailed {
+        image: String,
+        reason: String,
+        /// The last lines of the build output.
+        log_tail: Vec<String>,
+    },
+
+    #[error("{0}")]
+    Timeout(String),
+
+    #[error("{context}: {inner}")]
+    Context { context: String, inner: Box<Error> },
+
+    #[error("{0:#}")]
+    Other(#[from] anyhow::Error),
+}
+
+fn location(file: &Path, line: Option<usize>, column: Option<usize>) -> String {
+    match (line, column) {
+        (Some(line), Some(column)) => format!("{}:{}:{}", file.display(), line, column),
+        (Some(line), None) => format!("{}:{}", file.display(), line),
+        _ => file.display().to_string(),
+    }
+}
+
+impl Error {
+    /// Wraps the error with a message describing what was being done,
+    /// keeping its kind.
+    pub fn context<C: fmt::Display>(self, context: C) -> Error {
+        Error::Context {
+            context: context.to_string(),
+            inner: Box::new(self),
+        }
+    }
+
+    /// The error without any context.
+    pub fn kind(&self) -> &Error {
+        match self {
+            Error::Context { inner, .. } => inner.kind(),
+            err => err,
+        }
+    }
+
+    /// The exit code of the command line tool when it fails with this error.
+    pub fn exit_code(&self) -> i32 {
+        match self.kind() {
+            Error::Parse { .. } => 2,
+            Error::Connection(_) => 3,
+            Error::NotFound { .. } => 4,
+            Error::Conflict(_) => 5,
+            Error::BuildFailed { .. } => 6,
+            Error::Timeout(_) => 7,
+            _ => 1,
+        }
+    }
+}
+
+impl From<PodmanError> for Error {
+    fn from(err: PodmanError) -> Self {
+        match err {
+            PodmanError::ContainerNotFound { id, .. } => Error::NotFound {
+                kind: "container",
+                name: id,
+            },
+            PodmanError::ImageNotFound { id, .. } => Error::NotFound {
+                kind: "image",
+                name: id,
+            },
+            // Podman doesn't have a separate error for names that are taken.
+            PodmanError::Failed { ref reason } if reason.contains("already in use") => {
+                Error::Conflict(reason.clone())
+            }
+            PodmanError::Connection(_) => Error::Connection(err.into()),
+            err => Error::Other(err.into()),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Other(err.into())
+    }
+}
+
+impl From<crossterm::ErrorKind> for Error {
+    fn from(err: crossterm::ErrorKind) -> Self {
+        Error::Other(err.into())
+    }
+}
+
+impl From<ignore::Error> for Error {
+    fn from(err: ignore::Error) -> Self {
+        Error::Other(err.into())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Other(err.into())
+    }
+}
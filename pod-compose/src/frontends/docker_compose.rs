@@ -1,57 +1,514 @@
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_yaml;
 use std::{
-    collections::BTreeMap as Map,
-    fs::File,
+    collections::{BTreeMap as Map, BTreeSet as Set},
+    fmt, fs,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
+use super::{
+    extends,
+    schema::{self, SourceMap},
+    units::{parse_bytes, parse_duration},
+};
 use crate::{
-    models::{Composition, ContainerName, ContainerSpec, ImageBuildSpec, ImageName, ImagePullSpec},
+    errors::{self, Error},
+    models::{
+        self, Composition, ContainerName, ContainerSpec, HealthcheckTest, ImageBuildSpec,
+        ImageName, ImagePullSpec, NetworkAttachment, NetworkName, NetworkSpec, Resources,
+        RestartPolicy, Security, ServiceSpec, SubnetSpec,
+    },
     services::ComposerFrontend,
 };
 
+/// The network that services are connected to if they don't specify any.
+const DEFAULT_NETWORK: &str = "default";
+
+/// The extension field with podman specific settings, both at the top level
+/// and per service.
+const PODMAN_EXTENSION: &str = "x-pod-compose";
+
+/// How containers are named unless `container_name` is set, see
+/// `DockerComposeFrontend::naming_template`.
+const DEFAULT_NAMING_TEMPLATE: &str = "{project}_{service}_{index}";
+
+/// The naming scheme of docker-compose v2.
+pub const HYPHEN_NAMING_TEMPLATE: &str = "{project}-{service}-{number}";
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct DockerComposeFile {
-    pub version: String,
+    pub version: Option<String>,
     pub services: Map<String, Service>,
+
+    #[serde(default)]
+    pub networks: Map<String, Option<Network>>,
+
+    #[serde(default, rename = "x-pod-compose")]
+    pub podman: PodmanSettings,
+}
+
+/// The compose file format of a file, decided by its `version` key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Version {
+    /// Version 2.x, with its minor version.
+    V2(u32),
+    /// Version 3.x, with its minor version.
+    V3(u32),
+    /// The compose specification, which is followed by files without a
+    /// version.
+    Spec,
+}
+
+/// Service keys that only some file versions define, with the minor versions
+/// of 2.x and 3.x that added them, or `None` if a major version doesn't have
+/// them at all. The compose specification defines all of them.
+const VERSIONED_SERVICE_KEYS: &[(&str, Option<u32>, Option<u32>)] = &[
+    // Version 3 replaced these with `deploy.resources`.
+    ("mem_limit", Some(0), None),
+    ("memswap_limit", Some(0), None),
+    ("mem_reservation", Some(0), None),
+    ("mem_swappiness", Some(0), None),
+    ("cpus", Some(2), None),
+    ("cpu_shares", Some(0), None),
+    ("cpu_period", Some(0), None),
+    ("cpu_quota", Some(0), None),
+    ("cpuset", Some(0), None),
+    ("pids_limit", Some(1), None),
+    ("oom_score_adj", Some(0), None),
+    ("group_add", Some(0), None),
+    ("healthcheck", Some(1), Some(0)),
+    ("scale", Some(2), None),
+    ("deploy", None, Some(0)),
+];
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Network {
+    pub name: Option<String>,
+
+    pub driver: Option<String>,
+
+    #[serde(default)]
+    pub internal: bool,
+
+    pub ipam: Option<Ipam>,
+
+    #[serde(default)]
+    pub external: External,
+
+    #[serde(default)]
+    pub labels: MapList,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Ipam {
+    pub driver: Option<String>,
+
+    #[serde(default)]
+    pub config: Vec<IpamConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct IpamConfig {
+    pub subnet: String,
+
+    pub ip_range: Option<String>,
+
+    pub gateway: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum External {
+    Bool(bool),
+    Named { name: String },
+}
+
+impl Default for External {
+    fn default() -> Self {
+        External::Bool(false)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct Service {
+    /// The service is only enabled when one of these profiles is, or always
+    /// if there are none.
+    #[serde(default)]
+    pub profiles: Vec<String>,
+
     pub replicas: Option<u64>,
 
+    pub scale: Option<u64>,
+
+    pub container_name: Option<String>,
+
     pub image: Option<String>,
 
     pub build: Option<Build>,
 
+    pub pull_policy: Option<PullPolicy>,
+
+    #[serde(default)]
+    pub deploy: Deploy,
+
+    pub healthcheck: Option<Healthcheck>,
+
+    pub restart: Option<String>,
+
+    #[serde(default)]
+    pub networks: ServiceNetworks,
+
+    #[serde(default)]
+    pub depends_on: DependsOn,
+
+    #[serde(default)]
+    pub links: Vec<String>,
+
+    #[serde(default)]
+    pub extra_hosts: MapList,
+
+    pub command: Option<StringOrList>,
+
+    pub entrypoint: Option<StringOrList>,
+
+    pub working_dir: Option<String>,
+
+    pub user: Option<String>,
+
+    pub hostname: Option<String>,
+
+    pub domainname: Option<String>,
+
+    #[serde(default)]
+    pub tty: bool,
+
+    #[serde(default)]
+    pub stdin_open: bool,
+
+    pub mem_limit: Option<NumberOrString>,
+
+    pub memswap_limit: Option<NumberOrString>,
+
+    pub mem_reservation: Option<NumberOrString>,
+
+    pub mem_swappiness: Option<i64>,
+
+    pub cpus: Option<NumberOrString>,
+
+    pub cpu_shares: Option<u64>,
+
+    pub cpu_period: Option<NumberOrString>,
+
+    pub cpu_quota: Option<NumberOrString>,
+
+    pub cpuset: Option<String>,
+
+    pub pids_limit: Option<i64>,
+
+    pub shm_size: Option<NumberOrString>,
+
+    pub oom_score_adj: Option<i64>,
+
+    #[serde(default)]
+    pub cap_add: Vec<String>,
+
+    #[serde(default)]
+    pub cap_drop: Vec<String>,
+
+    #[serde(default)]
+    pub privileged: bool,
+
+    #[serde(default)]
+    pub read_only: bool,
+
+    #[serde(default)]
+    pub security_opt: Vec<String>,
+
+    pub userns_mode: Option<String>,
+
+    #[serde(default)]
+    pub group_add: Vec<NumberOrString>,
+
+    #[serde(default)]
+    pub devices: Vec<String>,
+
+    #[serde(default)]
+    pub sysctls: Sysctls,
+
+    #[serde(default)]
+    pub ulimits: Map<String, Ulimit>,
+
+    pub tmpfs: Option<StringOrList>,
+
+    #[serde(default)]
+    pub ports: Vec<Port>,
+
+    #[serde(default, rename = "x-pod-compose")]
+    pub podman: PodmanSettings,
+}
+
+/// Podman specific settings of a service. Settings at the top level apply to
+/// all services that don't set them themselves.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct PodmanSettings {
+    /// Whether to run the container in systemd mode, `true`, `false` or
+    /// `always`.
+    pub systemd: Option<String>,
+
+    pub uidmap: Option<Vec<String>>,
+
+    pub gidmap: Option<Vec<String>>,
+
+    pub annotations: Option<MapList>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Sysctls {
+    Map(Map<String, NumberOrString>),
+    List(Vec<String>),
+}
+
+impl Default for Sysctls {
+    fn default() -> Self {
+        Sysctls::List(Vec::new())
+    }
+}
+
+/// A published port, either `[[ip:]host:]container[/protocol]` or the long
+/// syntax.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+enum Port {
+    Short(NumberOrString),
+    Long(LongPort),
+}
+
+impl<'de> Deserialize<'de> for Port {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        schema::short_or_long(deserializer, Port::Short, Port::Long)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LongPort {
+    target: u16,
+    published: Option<NumberOrString>,
+    host_ip: Option<String>,
+    protocol: Option<String>,
+}
+
+/// Either a single value for both the soft and hard limit, or both of them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Ulimit {
+    Single(i64),
+    Limits { soft: i64, hard: i64 },
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+enum DependsOn {
+    List(Vec<String>),
+    Map(Map<String, DependsOnCondition>),
+}
+
+impl<'de> Deserialize<'de> for DependsOn {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        schema::short_or_long(deserializer, DependsOn::List, DependsOn::Map)
+    }
+}
+
+impl Default for DependsOn {
+    fn default() -> Self {
+        DependsOn::List(Vec::new())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DependsOnCondition {
+    pub condition: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+enum ServiceNetworks {
+    List(Vec<String>),
+    Map(Map<String, Option<ServiceNetwork>>),
+}
+
+impl<'de> Deserialize<'de> for ServiceNetworks {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        schema::short_or_long(deserializer, ServiceNetworks::List, ServiceNetworks::Map)
+    }
+}
+
+impl Default for ServiceNetworks {
+    fn default() -> Self {
+        ServiceNetworks::List(Vec::new())
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct ServiceNetwork {
+    #[serde(default)]
+    pub aliases: Vec<String>,
+
+    pub ipv4_address: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Healthcheck {
+    pub test: Option<StringOrList>,
+
+    pub interval: Option<String>,
+
+    pub timeout: Option<String>,
+
+    pub retries: Option<u64>,
+
+    pub start_period: Option<String>,
+
     #[serde(default)]
-    pub ports: Vec<String>,
+    pub disable: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Deploy {
+    pub replicas: Option<u64>,
+
+    pub update_config: Option<UpdateConfig>,
+
+    pub restart_policy: Option<DeployRestartPolicy>,
+
+    #[serde(default)]
+    pub resources: DeployResources,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct DeployResources {
+    #[serde(default)]
+    pub limits: ResourceValues,
+
+    #[serde(default)]
+    pub reservations: ResourceValues,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct ResourceValues {
+    pub cpus: Option<NumberOrString>,
+
+    pub memory: Option<NumberOrString>,
+
+    pub pids: Option<i64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+struct DeployRestartPolicy {
+    pub condition: Option<RestartCondition>,
+
+    pub delay: Option<String>,
+
+    pub max_attempts: Option<u64>,
+
+    pub window: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RestartCondition {
+    None,
+    OnFailure,
+    Any,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct UpdateConfig {
+    pub parallelism: Option<u64>,
+
+    pub delay: Option<String>,
+
+    pub order: Option<UpdateOrder>,
+
+    pub failure_action: Option<UpdateFailureAction>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum UpdateOrder {
+    StopFirst,
+    StartFirst,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum UpdateFailureAction {
+    Pause,
+    Continue,
+    Rollback,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PullPolicy {
+    Always,
+    Never,
+    Missing,
+    IfNotPresent,
+    /// The image is always built instead of pulled.
+    Build,
+}
+
+#[derive(Clone, Debug, Serialize)]
 #[serde(untagged)]
 enum Build {
     Short(String),
-    Extended {
-        context: String,
+    Extended(ExtendedBuild),
+}
 
-        dockerfile: Option<String>,
+impl<'de> Deserialize<'de> for Build {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        schema::short_or_long(deserializer, Build::Short, Build::Extended)
+    }
+}
 
-        #[serde(default)]
-        args: MapList,
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ExtendedBuild {
+    context: String,
 
-        #[serde(default)]
-        cache_from: Vec<String>,
+    dockerfile: Option<String>,
 
-        #[serde(default)]
-        labels: MapList,
+    #[serde(default)]
+    args: MapList,
 
-        shm_size: Option<String>,
+    #[serde(default)]
+    cache_from: Vec<String>,
 
-        target: Option<String>,
-    },
+    #[serde(default)]
+    labels: MapList,
+
+    shm_size: Option<NumberOrString>,
+
+    target: Option<String>,
+}
+
+/// A value that can be written both as a number and as a string with a unit,
+/// such as `memory: 512m` and `memory: 536870912`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum NumberOrString {
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StringOrList {
+    String(String),
+    List(Vec<String>),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -67,6 +524,581 @@ impl Default for MapList {
     }
 }
 
+impl UpdateConfig {
+    /// Replicas are updated in `default_order` unless the config says
+    /// otherwise.
+    fn to_model(&self, default_order: models::UpdateOrder) -> Result<models::UpdateConfig> {
+        let default = models::UpdateConfig::default();
+
+        let delay = match self.delay {
+            Some(ref delay) => parse_duration(delay)?,
+            None => default.delay,
+        };
+
+        let failure_action = match self.failure_action {
+            Some(UpdateFailureAction::Pause) => models::UpdateFailureAction::Pause,
+            Some(UpdateFailureAction::Continue) => models::UpdateFailureAction::Continue,
+            Some(UpdateFailureAction::Rollback) => models::UpdateFailureAction::Rollback,
+            None => default.failure_action,
+        };
+
+        let order = match self.order {
+            Some(UpdateOrder::StopFirst) => models::UpdateOrder::StopFirst,
+            Some(UpdateOrder::StartFirst) => models::UpdateOrder::StartFirst,
+            None => default_order,
+        };
+
+        Ok(models::UpdateConfig {
+            parallelism: self.parallelism.unwrap_or(default.parallelism),
+            delay,
+            failure_action,
+            order,
+        })
+    }
+}
+
+impl Service {
+    /// Replicas are replaced start-first so that they stay up, unless two
+    /// containers of the same replica can't run side by side since they
+    /// would have the same address.
+    fn default_update_order(&self) -> models::UpdateOrder {
+        let fixed_address = self
+            .networks
+            .to_map()
+            .values()
+            .any(|network| network.ipv4_address.is_some());
+
+        if fixed_address {
+            models::UpdateOrder::StopFirst
+        } else {
+            models::UpdateOrder::StartFirst
+        }
+    }
+
+    /// `deploy.restart_policy` takes precedence over `restart`, the same as
+    /// in docker-compose.
+    fn restart_policy(&self) -> Result<RestartPolicy> {
+        if let Some(ref restart_policy) = self.deploy.restart_policy {
+            let restart_policy = match restart_policy.condition {
+                Some(RestartCondition::None) => RestartPolicy::No,
+                Some(RestartCondition::OnFailure) => RestartPolicy::OnFailure {
+                    max_retries: restart_policy.max_attempts,
+                },
+                Some(RestartCondition::Any) | None => RestartPolicy::Always,
+            };
+
+            return Ok(restart_policy);
+        }
+
+        let restart = match self.restart {
+            Some(ref restart) => restart.as_str(),
+            None => return Ok(RestartPolicy::No),
+        };
+
+        let restart_policy = match restart.split_at(restart.find(':').unwrap_or(restart.len())) {
+            ("no", "") => RestartPolicy::No,
+            ("always", "") => RestartPolicy::Always,
+            ("unless-stopped", "") => RestartPolicy::UnlessStopped,
+            ("on-failure", "") => RestartPolicy::OnFailure { max_retries: None },
+            ("on-failure", max_retries) => {
+                let max_retries = max_retries[1..]
+                    .parse()
+                    .map_err(|_| anyhow!("invalid restart policy: {:?}", restart))?;
+                RestartPolicy::OnFailure {
+                    max_retries: Some(max_retries),
+                }
+            }
+            _ => return Err(anyhow!("invalid restart policy: {:?}", restart)),
+        };
+
+        Ok(restart_policy)
+    }
+}
+
+impl Version {
+    fn parse(version: Option<&str>) -> Result<Version> {
+        let version = match version {
+            Some(version) => version,
+            None => return Ok(Version::Spec),
+        };
+
+        let mut parts = version.splitn(2, '.');
+        let major = parts.next().unwrap_or_default();
+        let minor = match parts.next() {
+            Some(minor) => minor
+                .parse()
+                .map_err(|_| anyhow!("invalid compose file version {:?}", version))?,
+            None => 0,
+        };
+
+        match major {
+            "2" => Ok(Version::V2(minor)),
+            "3" => Ok(Version::V3(minor)),
+            _ => Err(anyhow!("unsupported compose file version {:?}", version)),
+        }
+    }
+
+    /// Whether this version defines something that was added in `2.v2` and
+    /// `3.v3`.
+    fn defines(self, v2: Option<u32>, v3: Option<u32>) -> bool {
+        match self {
+            Version::V2(minor) => matches!(v2, Some(since) if minor >= since),
+            Version::V3(minor) => matches!(v3, Some(since) if minor >= since),
+            Version::Spec => true,
+        }
+    }
+
+    /// Removes the service keys that this version doesn't define from a
+    /// compose file, so that they're ignored like docker-compose would
+    /// reject them. `undefined` is called with the path of every removed key.
+    fn remove_undefined_keys(
+        self,
+        document: &mut serde_yaml::Value,
+        mut undefined: impl FnMut(String, &str),
+    ) {
+        let services = match document.get_mut("services") {
+            Some(serde_yaml::Value::Mapping(services)) => services,
+            _ => return,
+        };
+
+        for (name, service) in services.iter_mut() {
+            let (name, service) = match (name.as_str(), service) {
+                (Some(name), serde_yaml::Value::Mapping(service)) => (name, service),
+                _ => continue,
+            };
+
+            for &(key, v2, v3) in VERSIONED_SERVICE_KEYS {
+                if !self.defines(v2, v3) && service.remove(&key.into()).is_some() {
+                    undefined(format!("services.{}.{}", name, key), key);
+                }
+            }
+
+            // Conditions were added in 2.1 and dropped again in version 3,
+            // where only the list form is allowed.
+            if !self.defines(Some(1), None) {
+                if let Some(depends_on) = service.get_mut(&"depends_on".into()) {
+                    if let serde_yaml::Value::Mapping(conditions) = depends_on {
+                        let services = conditions.iter().map(|(name, _)| name.clone()).collect();
+                        *depends_on = serde_yaml::Value::Sequence(services);
+                        undefined(
+                            format!("services.{}.depends_on", name),
+                            "depends_on with conditions",
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Version::V2(minor) => write!(f, "2.{}", minor),
+            Version::V3(minor) => write!(f, "3.{}", minor),
+            Version::Spec => write!(f, "compose-spec"),
+        }
+    }
+}
+
+impl Network {
+    fn to_model(&self, project_name: &str, key: &str) -> NetworkSpec {
+        let (name, external) = match self.external {
+            External::Bool(true) => (self.name.clone().unwrap_or_else(|| key.into()), true),
+            External::Named { ref name } => (name.clone(), true),
+            External::Bool(false) => (
+                self.name
+                    .clone()
+                    .unwrap_or_else(|| format!("{}_{}", project_name, key)),
+                false,
+            ),
+        };
+
+        let subnets = self
+            .ipam
+            .iter()
+            .flat_map(|ipam| ipam.config.iter())
+            .map(|config| SubnetSpec {
+                subnet: config.subnet.clone(),
+                gateway: config.gateway.clone(),
+                ip_range: config.ip_range.clone(),
+            })
+            .collect();
+
+        NetworkSpec {
+            name: NetworkName(name),
+            external,
+            driver: self.driver.clone(),
+            internal: self.internal,
+            subnets,
+            labels: self.labels.clone().to_map(),
+        }
+    }
+}
+
+impl ServiceNetworks {
+    /// Returns the networks of a service keyed by their names in the compose
+    /// file. Services without networks are connected to the default network.
+    fn to_map(&self) -> Map<String, ServiceNetwork> {
+        let networks: Map<_, _> = match self {
+            ServiceNetworks::List(networks) => networks
+                .iter()
+                .map(|network| (network.clone(), Default::default()))
+                .collect(),
+            ServiceNetworks::Map(networks) => networks
+                .iter()
+                .map(|(network, config)| (network.clone(), config.clone().unwrap_or_default()))
+                .collect(),
+        };
+
+        if networks.is_empty() {
+            let mut networks = Map::new();
+            networks.insert(DEFAULT_NETWORK.into(), Default::default());
+            return networks;
+        }
+
+        networks
+    }
+}
+
+impl Service {
+    /// Combines the resource limits from `deploy.resources` with the older
+    /// keys from version 2 compose files. Setting both to different values is
+    /// an error, like in docker-compose.
+    fn resources(&self) -> Result<Resources> {
+        fn merge<T: PartialEq + std::fmt::Debug>(
+            key: &str,
+            legacy_key: &str,
+            value: Option<T>,
+            legacy_value: Option<T>,
+        ) -> Result<Option<T>> {
+            match (value, legacy_value) {
+                (Some(value), Some(legacy_value)) if value != legacy_value => Err(anyhow!(
+                    "{} and {} are set to different values: {:?} and {:?}",
+                    key,
+                    legacy_key,
+                    value,
+                    legacy_value
+                )),
+                (value, legacy_value) => Ok(value.or(legacy_value)),
+            }
+        }
+
+        let limits = &self.deploy.resources.limits;
+        let reservations = &self.deploy.resources.reservations;
+
+        let memory = merge(
+            "deploy.resources.limits.memory",
+            "mem_limit",
+            limits.memory.as_ref().map(|m| m.to_bytes()).transpose()?,
+            self.mem_limit.as_ref().map(|m| m.to_bytes()).transpose()?,
+        )?;
+
+        let memory_reservation = merge(
+            "deploy.resources.reservations.memory",
+            "mem_reservation",
+            reservations
+                .memory
+                .as_ref()
+                .map(|m| m.to_bytes())
+                .transpose()?,
+            self.mem_reservation
+                .as_ref()
+                .map(|m| m.to_bytes())
+                .transpose()?,
+        )?;
+
+        let nano_cpus = merge(
+            "deploy.resources.limits.cpus",
+            "cpus",
+            limits.cpus.as_ref().map(|c| c.to_nano_cpus()).transpose()?,
+            self.cpus.as_ref().map(|c| c.to_nano_cpus()).transpose()?,
+        )?;
+
+        let pids_limit = merge(
+            "deploy.resources.limits.pids",
+            "pids_limit",
+            limits.pids,
+            self.pids_limit,
+        )?;
+
+        Ok(Resources {
+            memory,
+            memory_reservation,
+            memory_swap: self
+                .memswap_limit
+                .as_ref()
+                .map(|m| m.to_bytes_or_unlimited())
+                .transpose()?,
+            memory_swappiness: self.mem_swappiness,
+            nano_cpus,
+            cpu_shares: self.cpu_shares,
+            cpu_period: self
+                .cpu_period
+                .as_ref()
+                .map(|p| p.to_duration())
+                .transpose()?,
+            cpu_quota: self
+                .cpu_quota
+                .as_ref()
+                .map(|q| q.to_duration())
+                .transpose()?,
+            cpuset: self.cpuset.clone(),
+            pids_limit,
+            shm_size: self.shm_size.as_ref().map(|s| s.to_bytes()).transpose()?,
+            oom_score_adj: self.oom_score_adj,
+        })
+    }
+
+    /// The number of replicas from `deploy.replicas`, `scale` or
+    /// `replicas`, which have to agree if more than one of them is set.
+    fn replicas(&self) -> Result<u64> {
+        let mut replicas = None;
+        for &(key, value) in &[
+            ("deploy.replicas", self.deploy.replicas),
+            ("scale", self.scale),
+            ("replicas", self.replicas),
+        ] {
+            match (replicas, value) {
+                (Some((other_key, other_value)), Some(value)) if other_value != value => {
+                    return Err(anyhow!(
+                        "{} and {} are set to different values: {} and {}",
+                        other_key,
+                        key,
+                        other_value,
+                        value
+                    ));
+                }
+                (None, Some(value)) => replicas = Some((key, value)),
+                _ => (),
+            }
+        }
+
+        Ok(replicas.map_or(1, |(_, replicas)| replicas))
+    }
+
+    fn security(&self) -> Security {
+        // docker-compose also accepts the older `label:disable` form, podman
+        // only understands `label=disable`.
+        let security_opt = self
+            .security_opt
+            .iter()
+            .map(|opt| {
+                if opt.contains('=') {
+                    opt.clone()
+                } else {
+                    opt.replacen(':', "=", 1)
+                }
+            })
+            .collect();
+
+        let sysctls = match self.sysctls {
+            Sysctls::Map(ref sysctls) => sysctls
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect(),
+            Sysctls::List(ref sysctls) => sysctls.clone(),
+        };
+
+        let ulimits = self
+            .ulimits
+            .iter()
+            .map(|(name, ulimit)| match ulimit {
+                Ulimit::Single(limit) => format!("{}={}:{}", name, limit, limit),
+                Ulimit::Limits { soft, hard } => format!("{}={}:{}", name, soft, hard),
+            })
+            .collect();
+
+        let tmpfs = match self.tmpfs {
+            Some(StringOrList::String(ref tmpfs)) => vec![tmpfs.clone()],
+            Some(StringOrList::List(ref tmpfs)) => tmpfs.clone(),
+            None => Vec::new(),
+        };
+
+        Security {
+            cap_add: self.cap_add.clone(),
+            cap_drop: self.cap_drop.clone(),
+            privileged: self.privileged,
+            read_only: self.read_only,
+            security_opt,
+            userns_mode: self.userns_mode.clone(),
+            group_add: self.group_add.iter().map(|g| g.to_string()).collect(),
+            devices: self.devices.clone(),
+            sysctls,
+            ulimits,
+            tmpfs,
+        }
+    }
+}
+
+impl Port {
+    /// The host port, if it's a single port rather than a range or a port
+    /// picked by podman.
+    fn fixed_host_port(&self) -> Option<String> {
+        let host_port = match self {
+            Port::Short(port) => {
+                let port = port.to_string();
+                let port = port.split('/').next().unwrap_or_default();
+                // The host IP may contain colons itself.
+                let mut parts = port.rsplitn(3, ':');
+                parts.next();
+                parts.next().unwrap_or_default().to_owned()
+            }
+            Port::Long(LongPort { published, .. }) => published
+                .as_ref()
+                .map(|published| published.to_string())
+                .unwrap_or_default(),
+        };
+
+        if host_port.is_empty() || host_port.contains('-') {
+            None
+        } else {
+            Some(host_port)
+        }
+    }
+}
+
+impl PodmanSettings {
+    fn to_model(&self, defaults: &PodmanSettings) -> models::PodmanOptions {
+        let annotations = self.annotations.as_ref().or(defaults.annotations.as_ref());
+
+        models::PodmanOptions {
+            systemd: self.systemd.clone().or_else(|| defaults.systemd.clone()),
+            uidmap: self
+                .uidmap
+                .as_ref()
+                .or(defaults.uidmap.as_ref())
+                .cloned()
+                .unwrap_or_default(),
+            gidmap: self
+                .gidmap
+                .as_ref()
+                .or(defaults.gidmap.as_ref())
+                .cloned()
+                .unwrap_or_default(),
+            annotations: annotations
+                .cloned()
+                .unwrap_or_default()
+                .to_map()
+                .into_iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect(),
+        }
+    }
+}
+
+impl DependsOn {
+    fn services(&self) -> Set<String> {
+        match self {
+            DependsOn::List(services) => services.iter().cloned().collect(),
+            DependsOn::Map(services) => services.keys().cloned().collect(),
+        }
+    }
+}
+
+impl Healthcheck {
+    fn to_model(&self) -> Result<Option<models::Healthcheck>> {
+        if self.disable {
+            return Ok(Some(models::Healthcheck::disabled()));
+        }
+
+        let test = match self.test {
+            Some(StringOrList::String(ref command)) => HealthcheckTest::Shell(command.clone()),
+            Some(StringOrList::List(ref test)) => match test.split_first() {
+                Some((kind, _)) if kind == "NONE" => HealthcheckTest::None,
+                Some((kind, command)) if kind == "CMD" && !command.is_empty() => {
+                    HealthcheckTest::Command(command.to_vec())
+                }
+                Some((kind, command)) if kind == "CMD-SHELL" && !command.is_empty() => {
+                    HealthcheckTest::Shell(command.join(" "))
+                }
+                _ => return Err(anyhow!("invalid healthcheck test: {:?}", test)),
+            },
+            None => return Ok(None),
+        };
+
+        let duration = |duration: &Option<String>| -> Result<Option<Duration>> {
+            duration.as_ref().map(|d| parse_duration(d)).transpose()
+        };
+
+        Ok(Some(models::Healthcheck {
+            test,
+            interval: duration(&self.interval)?,
+            timeout: duration(&self.timeout)?,
+            retries: self.retries,
+            start_period: duration(&self.start_period)?,
+        }))
+    }
+}
+
+impl NumberOrString {
+    fn to_bytes(&self) -> Result<u64> {
+        match self {
+            NumberOrString::Integer(bytes) if *bytes >= 0 => Ok(*bytes as u64),
+            NumberOrString::Float(bytes) if *bytes >= 0.0 => Ok(*bytes as u64),
+            NumberOrString::String(bytes) => parse_bytes(bytes),
+            _ => Err(anyhow!("invalid byte value: {:?}", self)),
+        }
+    }
+
+    /// Like `to_bytes`, but also allows -1 for unlimited.
+    fn to_bytes_or_unlimited(&self) -> Result<i64> {
+        match self {
+            NumberOrString::Integer(-1) => Ok(-1),
+            NumberOrString::String(bytes) if bytes == "-1" => Ok(-1),
+            _ => self.to_bytes().map(|bytes| bytes as i64),
+        }
+    }
+
+    fn to_nano_cpus(&self) -> Result<u64> {
+        let cpus = match self {
+            NumberOrString::Integer(cpus) => *cpus as f64,
+            NumberOrString::Float(cpus) => *cpus,
+            NumberOrString::String(cpus) => cpus
+                .parse()
+                .map_err(|_| anyhow!("invalid number of cpus: {:?}", cpus))?,
+        };
+
+        if cpus < 0.0 {
+            return Err(anyhow!("invalid number of cpus: {:?}", cpus));
+        }
+
+        Ok((cpus * 1e9).round() as u64)
+    }
+
+    /// Plain numbers are in microseconds, like in docker-compose.
+    fn to_duration(&self) -> Result<Duration> {
+        match self {
+            NumberOrString::Integer(micros) if *micros >= 0 => {
+                Ok(Duration::from_micros(*micros as u64))
+            }
+            NumberOrString::String(duration) => parse_duration(duration),
+            _ => Err(anyhow!("invalid duration: {:?}", self)),
+        }
+    }
+}
+
+impl fmt::Display for NumberOrString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NumberOrString::Integer(value) => value.fmt(f),
+            NumberOrString::Float(value) => value.fmt(f),
+            NumberOrString::String(value) => value.fmt(f),
+        }
+    }
+}
+
+impl StringOrList {
+    /// Strings are split into arguments the same way a shell would.
+    fn to_args(&self) -> Result<Vec<String>> {
+        match self {
+            StringOrList::String(command) => shell_words::split(command)
+                .map_err(|err| anyhow!("could not parse {:?}: {}", command, err)),
+            StringOrList::List(args) => Ok(args.clone()),
+        }
+    }
+}
+
 impl MapList {
     pub fn to_map(self) -> Map<String, String> {
         match self {
@@ -87,28 +1119,342 @@ impl MapList {
     }
 }
 
-pub struct DockerComposeFrontend;
+pub struct DockerComposeFrontend {
+    /// The enabled profiles.
+    profiles: Set<String>,
+    /// Services that are enabled whatever their profiles are.
+    enabled_services: Set<String>,
+    /// Overrides the number of replicas of services.
+    scale: Map<String, u64>,
+    naming_template: String,
+}
 
 impl DockerComposeFrontend {
     pub fn new() -> DockerComposeFrontend {
-        DockerComposeFrontend
+        DockerComposeFrontend {
+            profiles: Set::new(),
+            enabled_services: Set::new(),
+            scale: Map::new(),
+            naming_template: DEFAULT_NAMING_TEMPLATE.into(),
+        }
+    }
+
+    /// Sets the template for container names. `{project}` and `{service}`
+    /// are replaced with the project and service names, `{index}` with the
+    /// index of the replica starting at 0, and `{number}` with the index
+    /// starting at 1.
+    pub fn naming_template<S: Into<String>>(mut self, template: S) -> Self {
+        self.naming_template = template.into();
+        self
+    }
+
+    /// Enables the services with any of these profiles.
+    pub fn profiles<I: IntoIterator<Item = String>>(mut self, profiles: I) -> Self {
+        self.profiles = profiles.into_iter().collect();
+        self
+    }
+
+    /// Enables these services even if none of their profiles is, like the
+    /// services that are named on the command line in docker-compose.
+    pub fn enabled_services<I: IntoIterator<Item = String>>(mut self, services: I) -> Self {
+        self.enabled_services = services.into_iter().collect();
+        self
+    }
+
+    /// Overrides the number of replicas of services, like `up --scale`.
+    pub fn scale<I: IntoIterator<Item = (String, u64)>>(mut self, scale: I) -> Self {
+        self.scale = scale.into_iter().collect();
+        self
+    }
+}
+
+/// A compose file that has been parsed, but not deserialized yet, so that it
+/// can still be changed by `extends`.
+pub(super) struct Document {
+    pub path: PathBuf,
+    pub value: serde_yaml::Value,
+    pub source_map: SourceMap,
+}
+
+impl Document {
+    /// Parses a compose file, applies its merge keys and removes the keys its
+    /// version doesn't define.
+    pub fn load(path: &Path) -> errors::Result<Document> {
+        let source = fs::read_to_string(path).map_err(|err| {
+            Error::from(err).context(format!("could not read {}", path.display()))
+        })?;
+
+        let mut value: serde_yaml::Value = serde_yaml::from_str(&source).map_err(|err| {
+            let location = err.location();
+            Error::Parse {
+                file: path.into(),
+                line: location.as_ref().map(|location| location.line()),
+                column: location.as_ref().map(|location| location.column()),
+                message: err.to_string(),
+            }
+        })?;
+
+        let source_map = SourceMap::new(&source);
+        schema::apply_merge_keys(&mut value, "").map_err(|key| {
+            source_map.error(path, &key, "must be a mapping or a list of mappings")
+        })?;
+
+        let version = value.get("version").and_then(|version| version.as_str());
+        let version = Version::parse(version)
+            .map_err(|err| source_map.error(path, "version", &err.to_string()))?;
+
+        version.remove_undefined_keys(&mut value, |key_path, key| {
+            let message = format!(
+                "{} is not defined by compose file version {} and will be ignored",
+                key, version
+            );
+            source_map.warn(path, &key_path, &message);
+        });
+
+        Ok(Document {
+            path: path.into(),
+            value,
+            source_map,
+        })
+    }
+
+    pub fn error(&self, path: &str, message: &str) -> Error {
+        self.source_map.error(&self.path, path, message)
     }
 }
 
 impl ComposerFrontend for DockerComposeFrontend {
-    fn composition(&mut self, project_name: &str, compose_file_path: &Path) -> Result<Composition> {
-        let compose_file = File::open(&compose_file_path)?;
+    fn composition(
+        &mut self,
+        project_name: &str,
+        compose_file_path: &Path,
+    ) -> errors::Result<Composition> {
+        let mut document = Document::load(compose_file_path)?;
+        extends::resolve_extends(&mut document)?;
+
+        let source_map = &document.source_map;
+        schema::validate(compose_file_path, source_map, &document.value)?;
+
+        // Anything that passed validation but is ignored while deserializing
+        // is part of the spec, but not supported by pod-compose.
+        let mut ignored = Vec::new();
+        let source = serde_yaml::to_string(&document.value).map_err(anyhow::Error::from)?;
+        let file: DockerComposeFile = schema::from_str_reporting_ignored(&source, &mut ignored)
+            .map_err(|(path, err)| {
+                source_map.error(compose_file_path, &path, &deserialize_message(&path, &err))
+            })?;
+
+        // Extension fields are meant to be ignored, except for our own.
+        let ignored = ignored.into_iter().filter(|path| {
+            !path
+                .split('.')
+                .any(|key| key.starts_with("x-") && key != PODMAN_EXTENSION)
+        });
+
+        for path in ignored {
+            let message = format!("{} is not supported and will be ignored", path);
+            source_map.warn(compose_file_path, &path, &message);
+        }
+
+        let warn = |path: &str, message: &str| source_map.warn(compose_file_path, path, message);
+        file.composition(project_name, self, &warn).map_err(|err| {
+            match err.downcast::<PathError>() {
+                Ok(err) => source_map.error(compose_file_path, &err.path, &err.message),
+                Err(err) => Error::Parse {
+                    file: compose_file_path.into(),
+                    line: None,
+                    column: None,
+                    message: format!("{:#}", err),
+                },
+            }
+        })
+    }
+}
 
-        let file: DockerComposeFile = serde_yaml::from_reader(compose_file)?;
+/// An error in the value at a YAML path of a compose file, so that it can be
+/// reported with the location of that value.
+#[derive(Debug, thiserror::Error)]
+#[error("{path}: {message}")]
+struct PathError {
+    path: String,
+    message: String,
+}
+
+fn path_error(path: String, message: impl std::fmt::Display) -> anyhow::Error {
+    PathError {
+        path,
+        message: message.to_string(),
+    }
+    .into()
+}
+
+/// Locates an error at a YAML path, for use with `map_err`.
+fn at_path(path: String) -> impl FnOnce(anyhow::Error) -> anyhow::Error {
+    move |err| path_error(path, format!("{:#}", err))
+}
+
+/// Prints the warning that a key is ignored, in the same format as the
+/// warnings about keys that are dropped while deserializing.
+fn warn_unsupported(warn: &dyn Fn(&str, &str), path: &str) {
+    warn(
+        path,
+        &format!("{} is not supported and will be ignored", path),
+    );
+}
+
+/// The message of a deserialization error, without the path and location
+/// serde_yaml adds to it, since they refer to the re-serialized document.
+fn deserialize_message(path: &str, err: &serde_yaml::Error) -> String {
+    let mut message = err.to_string();
+
+    if let Some(location) = err.location() {
+        let suffix = format!(" at line {} column {}", location.line(), location.column());
+        if message.ends_with(&suffix) {
+            message.truncate(message.len() - suffix.len());
+        }
+    }
+
+    // serde_yaml's path stops at the closest value it knows the path of.
+    match message.find(": ") {
+        Some(index) if path.starts_with(&message[..index]) => message[index + 2..].to_owned(),
+        _ => message,
+    }
+}
+
+impl DockerComposeFrontend {
+    fn check_naming_template(&self) -> Result<()> {
+        let template = &self.naming_template;
+
+        let rest = ["{project}", "{service}", "{index}", "{number}"]
+            .iter()
+            .fold(template.clone(), |rest, placeholder| {
+                rest.replace(placeholder, "")
+            });
+        if rest.contains('{') || rest.contains('}') {
+            return Err(anyhow!(
+                "invalid naming template {:?}, only {{project}}, {{service}}, {{index}} and {{number}} can be used",
+                template
+            ));
+        }
+
+        // Otherwise replicas and services could end up with the same names.
+        let has_index = template.contains("{index}") || template.contains("{number}");
+        if !template.contains("{service}") || !has_index {
+            return Err(anyhow!(
+                "invalid naming template {:?}, it must contain {{service}} and {{index}} or {{number}}",
+                template
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn container_name(&self, project_name: &str, service_name: &str, index: u64) -> String {
+        self.naming_template
+            .replace("{project}", project_name)
+            .replace("{service}", service_name)
+            .replace("{index}", &index.to_string())
+            .replace("{number}", &(index + 1).to_string())
+    }
+}
+
+impl DockerComposeFile {
+    /// Builds the composition, printing warnings about the settings it
+    /// ignores through `warn` with their YAML paths.
+    fn composition(
+        self,
+        project_name: &str,
+        frontend: &DockerComposeFrontend,
+        warn: &dyn Fn(&str, &str),
+    ) -> Result<Composition> {
+        let mut file = self;
         let mut composition: Composition = Default::default();
 
+        frontend.check_naming_template()?;
+
+        for service_name in frontend.scale.keys() {
+            if !file.services.contains_key(service_name) {
+                return Err(anyhow!("can't scale undefined service {:?}", service_name));
+            }
+        }
+
+        let profiles = &frontend.profiles;
+        let (services, disabled_services) =
+            file.services
+                .into_iter()
+                .partition::<Map<_, _>, _>(|(service_name, service)| {
+                    frontend.enabled_services.contains(service_name)
+                        || service.profiles.is_empty()
+                        || service
+                            .profiles
+                            .iter()
+                            .any(|profile| profiles.contains(profile))
+                });
+        file.services = services;
+        composition.disabled_services = disabled_services.keys().cloned().collect();
+
+        for (service_name, service) in file.services.iter() {
+            let links = service.links.iter().map(|link| match link.find(':') {
+                Some(index) => link[..index].to_owned(),
+                None => link.clone(),
+            });
+
+            let disabled_dependency = service
+                .depends_on
+                .services()
+                .into_iter()
+                .chain(links)
+                .find_map(|dependency| disabled_services.get_key_value(&dependency));
+
+            if let Some((dependency, disabled)) = disabled_dependency {
+                let key = if service.depends_on.services().contains(dependency) {
+                    "depends_on"
+                } else {
+                    "links"
+                };
+                return Err(path_error(
+                    format!("services.{}.{}", service_name, key),
+                    format!(
+                        "{:?} is disabled, enable one of its profiles: {}",
+                        dependency,
+                        disabled.profiles.join(", ")
+                    ),
+                ));
+            }
+        }
+
+        let mut networks = file
+            .networks
+            .iter()
+            .map(|(key, network)| {
+                let network = network.clone().unwrap_or_default();
+                let ipam_driver = network.ipam.as_ref().and_then(|ipam| ipam.driver.as_ref());
+                if ipam_driver.is_some() {
+                    warn_unsupported(warn, &format!("networks.{}.ipam.driver", key));
+                }
+                (key.clone(), network.to_model(project_name, key))
+            })
+            .collect::<Map<_, _>>();
+
+        if !networks.contains_key(DEFAULT_NETWORK) {
+            let network = Network::default().to_model(project_name, DEFAULT_NETWORK);
+            networks.insert(DEFAULT_NETWORK.into(), network);
+        }
+
+        let mut used_networks = Set::new();
+
         for (service_name, service) in file.services {
             let image_name = match service.image {
-                Some(image_name) => ImageName(image_name),
+                Some(ref image_name) => ImageName(image_name.clone()),
                 None => ImageName(format!("{}_{}", project_name, service_name)),
             };
 
-            match service.build {
+            // Images built under a name of their own can be pushed.
+            if service.build.is_some() && service.image.is_some() {
+                composition.push_images.push(image_name.clone());
+            }
+
+            match service.build.clone() {
                 Some(Build::Short(context)) => {
                     let image_spec = ImageBuildSpec {
                         name: image_name.clone(),
@@ -117,25 +1463,29 @@ impl ComposerFrontend for DockerComposeFrontend {
                         target: None,
                         build_args: Default::default(),
                         labels: Default::default(),
+                        shm_size: None,
                     };
                     composition.build_images.push(image_spec);
                 }
-                Some(Build::Extended {
-                    context,
-                    dockerfile,
-                    args,
-                    target,
-                    ..
-                }) => {
+                Some(Build::Extended(build)) => {
+                    // Podman's varlink interface can't build from a cache.
+                    if !build.cache_from.is_empty() {
+                        let path = format!("services.{}.build.cache_from", service_name);
+                        warn_unsupported(warn, &path);
+                    }
+
                     let image_spec = ImageBuildSpec {
                         name: image_name.clone(),
-                        context: PathBuf::from(context),
+                        context: PathBuf::from(build.context),
                         dockerfile: PathBuf::from(
-                            dockerfile.unwrap_or_else(|| "Dockerfile".into()),
+                            build.dockerfile.unwrap_or_else(|| "Dockerfile".into()),
                         ),
-                        target: target,
-                        build_args: args.to_map(),
-                        labels: Default::default(),
+                        target: build.target,
+                        build_args: build.args.to_map(),
+                        labels: build.labels.to_map(),
+                        shm_size: build.shm_size.map(|s| s.to_bytes()).transpose().map_err(
+                            at_path(format!("services.{}.build.shm_size", service_name)),
+                        )?,
                     };
                     composition.build_images.push(image_spec);
                 }
@@ -147,17 +1497,229 @@ impl ComposerFrontend for DockerComposeFrontend {
                 }
             }
 
-            for index in 0..service.replicas.unwrap_or(1) {
+            // Dependencies are only started before the services that depend
+            // on them, nothing waits for them to be healthy or to complete.
+            if let DependsOn::Map(ref dependencies) = service.depends_on {
+                for (dependency, condition) in dependencies.iter() {
+                    match condition.condition.as_deref() {
+                        None | Some("service_started") => (),
+                        Some(condition) => {
+                            let path = format!(
+                                "services.{}.depends_on.{}.condition",
+                                service_name, dependency
+                            );
+                            let message = format!(
+                                "{} {} is not supported, service_started is used instead",
+                                path, condition
+                            );
+                            warn(&path, &message);
+                        }
+                    }
+                }
+            }
+
+            let update_order = service.default_update_order();
+            let update_config = match service.deploy.update_config {
+                Some(ref update_config) => {
+                    update_config
+                        .to_model(update_order)
+                        .map_err(at_path(format!(
+                            "services.{}.deploy.update_config",
+                            service_name
+                        )))?
+                }
+                None => models::UpdateConfig {
+                    order: update_order,
+                    ..Default::default()
+                },
+            };
+
+            // Links are either `service` or `service:alias`.
+            let links = service
+                .links
+                .iter()
+                .map(|link| match link.find(':') {
+                    Some(index) => (link[index + 1..].to_owned(), link[..index].to_owned()),
+                    None => (link.clone(), link.clone()),
+                })
+                .collect();
+
+            let (pull_policy, build_policy) = match service.pull_policy {
+                Some(PullPolicy::Always) => (Some(models::PullPolicy::Always), None),
+                Some(PullPolicy::Never) => (Some(models::PullPolicy::Never), None),
+                Some(PullPolicy::Missing) | Some(PullPolicy::IfNotPresent) => {
+                    (Some(models::PullPolicy::IfNotPresent), None)
+                }
+                Some(PullPolicy::Build) if service.build.is_none() => {
+                    return Err(path_error(
+                        format!("services.{}.pull_policy", service_name),
+                        "is build, but the service has no build section",
+                    ));
+                }
+                Some(PullPolicy::Build) => (None, Some(models::BuildPolicy::Always)),
+                None => (None, None),
+            };
+
+            composition.services.insert(
+                service_name.clone(),
+                ServiceSpec {
+                    update_config,
+                    depends_on: service.depends_on.services(),
+                    links,
+                    pull_policy,
+                    build_policy,
+                },
+            );
+
+            // Extra hosts are either a list of `hostname:ip` or a map.
+            let extra_hosts = match service.extra_hosts {
+                MapList::Map(ref hosts) => hosts
+                    .iter()
+                    .map(|(host, ip)| format!("{}:{}", host, ip))
+                    .collect(),
+                MapList::List(ref hosts) => hosts.clone(),
+            };
+
+            let healthcheck = match service.healthcheck {
+                Some(ref healthcheck) => {
+                    let path = format!("services.{}.healthcheck", service_name);
+                    if healthcheck.test.is_none() && !healthcheck.disable {
+                        warn(&path, &format!("{} has no test and will be ignored", path));
+                    }
+                    healthcheck.to_model().map_err(at_path(path))?
+                }
+                None => None,
+            };
+
+            let restart_path = match service.deploy.restart_policy {
+                Some(ref restart_policy) => {
+                    let path = format!("services.{}.deploy.restart_policy", service_name);
+                    if restart_policy.delay.is_some() {
+                        warn_unsupported(warn, &format!("{}.delay", path));
+                    }
+                    if restart_policy.window.is_some() {
+                        warn_unsupported(warn, &format!("{}.window", path));
+                    }
+                    path
+                }
+                None => format!("services.{}.restart", service_name),
+            };
+            let restart_policy = service.restart_policy().map_err(at_path(restart_path))?;
+
+            let mut service_networks = Map::new();
+            for (key, network) in service.networks.to_map() {
+                let network_spec = networks.get(&key).ok_or_else(|| {
+                    path_error(
+                        format!("services.{}.networks.{}", service_name, key),
+                        "refers to an undefined network",
+                    )
+                })?;
+                used_networks.insert(key);
+
+                let attachment = NetworkAttachment {
+                    aliases: network.aliases,
+                    ipv4_address: network.ipv4_address,
+                };
+                service_networks.insert(network_spec.name.clone(), attachment);
+            }
+
+            let command = service
+                .command
+                .as_ref()
+                .map(|c| c.to_args())
+                .transpose()
+                .map_err(at_path(format!("services.{}.command", service_name)))?;
+            let entrypoint = service
+                .entrypoint
+                .as_ref()
+                .map(|e| e.to_args())
+                .transpose()
+                .map_err(at_path(format!("services.{}.entrypoint", service_name)))?;
+
+            let reservations = &service.deploy.resources.reservations;
+            let reservations_path =
+                format!("services.{}.deploy.resources.reservations", service_name);
+            if reservations.cpus.is_some() {
+                warn_unsupported(warn, &format!("{}.cpus", reservations_path));
+            }
+            if reservations.pids.is_some() {
+                warn_unsupported(warn, &format!("{}.pids", reservations_path));
+            }
+
+            let resources = service
+                .resources()
+                .map_err(at_path(format!("services.{}", service_name)))?;
+            let security = service.security();
+            let podman = service.podman.to_model(&file.podman);
+
+            // Ports are only checked for conflicts between replicas.
+            if !service.ports.is_empty() {
+                warn_unsupported(warn, &format!("services.{}.ports", service_name));
+            }
+
+            let replicas = match frontend.scale.get(&service_name) {
+                Some(replicas) => *replicas,
+                None => service
+                    .replicas()
+                    .map_err(at_path(format!("services.{}", service_name)))?,
+            };
+
+            if replicas > 1 {
+                if service.container_name.is_some() {
+                    return Err(path_error(
+                        format!("services.{}.container_name", service_name),
+                        format!("can't be used by {} replicas", replicas),
+                    ));
+                }
+
+                if let Some(port) = service.ports.iter().find_map(Port::fixed_host_port) {
+                    return Err(path_error(
+                        format!("services.{}.ports", service_name),
+                        format!("host port {} can't be used by {} replicas", port, replicas),
+                    ));
+                }
+            }
+
+            for index in 0..replicas {
+                let name = match service.container_name {
+                    Some(ref container_name) => container_name.clone(),
+                    None => frontend.container_name(project_name, &service_name, index),
+                };
+
                 let container = ContainerSpec {
                     service_name: service_name.clone(),
+                    replica: index,
                     image_name: image_name.clone(),
-                    name: ContainerName(format!("{}_{}_{}", project_name, service_name, index)),
+                    name: ContainerName(name),
                     labels: Default::default(),
+                    command: command.clone(),
+                    entrypoint: entrypoint.clone(),
+                    working_dir: service.working_dir.clone(),
+                    user: service.user.clone(),
+                    hostname: service.hostname.clone(),
+                    domainname: service.domainname.clone(),
+                    tty: service.tty,
+                    stdin_open: service.stdin_open,
+                    resources: resources.clone(),
+                    security: security.clone(),
+                    podman: podman.clone(),
+                    healthcheck: healthcheck.clone(),
+                    restart_policy,
+                    networks: service_networks.clone(),
+                    extra_hosts: extra_hosts.clone(),
                 };
                 composition.containers.push(container);
             }
         }
 
+        // Like docker-compose, only the networks that are used by any service
+        // are created.
+        composition.networks = networks
+            .into_iter()
+            .filter(|(key, _)| used_networks.contains(key))
+            .map(|(_, network)| network)
+            .collect();
+
         Ok(composition)
     }
 }
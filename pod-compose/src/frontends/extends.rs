@@ -0,0 +1,542 @@
+use serde_yaml::{Mapping, Value};
+use std::{
+    collections::BTreeMap as Map,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use super::docker_compose::Document;
+use crate::errors::Result;
+
+/// Keys whose lists are concatenated when a service extends another, instead
+/// of being replaced.
+const SEQUENCE_KEYS: &[&str] = &[
+    "cap_add",
+    "cap_drop",
+    "devices",
+    "dns",
+    "dns_search",
+    "env_file",
+    "expose",
+    "external_links",
+    "group_add",
+    "links",
+    "ports",
+    "security_opt",
+    "tmpfs",
+    "volumes",
+];
+
+/// Keys with both a list and a mapping form, and the separator between the
+/// key and the value in the list form.
+const MAPPING_KEYS: &[(&str, char)] = &[
+    ("environment", '='),
+    ("extra_hosts", ':'),
+    ("labels", '='),
+    ("sysctls", '='),
+];
+
+/// Replaces every service that extends another with the merged service.
+pub fn resolve_extends(document: &mut Document) -> Result<()> {
+    let names = match document.value.get("services") {
+        Some(Value::Mapping(services)) => services
+            .iter()
+            .filter_map(|(name, _)| name.as_str().map(String::from))
+            .collect::<Vec<_>>(),
+        _ => return Ok(()),
+    };
+
+    let mut resolver = Resolver {
+        documents: Map::new(),
+    };
+
+    let mut services = Mapping::new();
+    for name in names {
+        let service = resolver.resolve(document, &name, &mut Vec::new())?;
+        services.insert(name.into(), Value::Mapping(service));
+    }
+
+    if let Some(Value::Mapping(document_services)) = document.value.get_mut("services") {
+        for (name, service) in services {
+            document_services.insert(name, service);
+        }
+    }
+
+    Ok(())
+}
+
+struct Resolver {
+    /// The other files that services extend, by their canonical paths.
+    documents: Map<PathBuf, Rc<Document>>,
+}
+
+impl Resolver {
+    /// Resolves a service of a document, with the services that are being
+    /// resolved in `stack` to detect cycles.
+    fn resolve(
+        &mut self,
+        document: &Document,
+        name: &str,
+        stack: &mut Vec<(PathBuf, String)>,
+    ) -> Result<Mapping> {
+        let service = document
+            .value
+            .get("services")
+            .and_then(|services| services.get(name));
+        let mut service = match service {
+            Some(Value::Mapping(service)) => service.clone(),
+            _ => Mapping::new(),
+        };
+
+        let extends = match service.remove(&"extends".into()) {
+            Some(extends) => extends,
+            None => return Ok(service),
+        };
+
+        let path = format!("services.{}.extends", name);
+        let (file, base_name) = match extends {
+            Value::String(base_name) => (None, base_name),
+            Value::Mapping(ref extends) => {
+                let file = extends.get(&"file".into()).and_then(|file| file.as_str());
+                let base_name = extends
+                    .get(&"service".into())
+                    .and_then(|name| name.as_str());
+                match base_name {
+                    Some(base_name) => (file, base_name.to_owned()),
+                    None => return Err(document.error(&path, "must name a service")),
+                }
+            }
+            _ => return Err(document.error(&path, "must be a service name or a mapping")),
+        };
+
+        let current = (canonical_path(&document.path), name.to_owned());
+        if stack.contains(&current) {
+            let mut cycle = stack
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .collect::<Vec<_>>();
+            cycle.push(name);
+            let message = format!("extends services in a cycle: {}", cycle.join(" -> "));
+            return Err(document.error(&path, &message));
+        }
+
+        let base_document = match file {
+            Some(file) => {
+                let directory = document.path.parent().unwrap_or_else(|| Path::new(""));
+                Some(self.load(&directory.join(file)).map_err(|err| {
+                    document.error(&path, &format!("could not load {}: {}", file, err))
+                })?)
+            }
+            None => None,
+        };
+        let base_document = base_document.as_deref().unwrap_or(document);
+
+        let is_defined = base_document
+            .value
+            .get("services")
+            .and_then(|services| services.get(base_name.as_str()))
+            .is_some();
+        if !is_defined {
+            let message = format!(
+                "extends service {:?}, which isn't defined in {}",
+                base_name,
+                base_document.path.display()
+            );
+            return Err(document.error(&path, &message));
+        }
+
+        stack.push(current);
+        let mut base = self.resolve(base_document, &base_name, stack)?;
+        stack.pop();
+
+        // Relative paths are relative to the file that defined them.
+        let base_directory = base_document.path.parent();
+        if base_directory != document.path.parent() {
+            if let Some(base_directory) = base_directory {
+                resolve_relative_paths(&mut base, base_directory);
+            }
+        }
+
+        Ok(merge_mappings(base, service))
+    }
+
+    fn load(&mut self, path: &Path) -> Result<Rc<Document>> {
+        let key = canonical_path(path);
+        if let Some(document) = self.documents.get(&key) {
+            return Ok(document.clone());
+        }
+
+        let document = Rc::new(Document::load(path)?);
+        self.documents.insert(key, document.clone());
+        Ok(document)
+    }
+}
+
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// Merges a mapping into the mapping it extends, following the compose merge
+/// rules: mappings are merged, some lists are concatenated and anything else
+/// is overridden.
+fn merge_mappings(base: Mapping, mapping: Mapping) -> Mapping {
+    let mut merged = base;
+    for (key, value) in mapping {
+        let value = match merged.remove(&key) {
+            Some(base) => merge_values(key.as_str().unwrap_or_default(), base, value),
+            None => value,
+        };
+        merged.insert(key, value);
+    }
+    merged
+}
+
+fn merge_values(key: &str, base: Value, value: Value) -> Value {
+    // Keys that have a mapping form are always merged in it, other values
+    // only when their forms differ.
+    let (base, value) = match (base, value) {
+        (base, value) if MAPPING_KEYS.iter().any(|(name, _)| *name == key) => {
+            (to_mapping_form(key, base), to_mapping_form(key, value))
+        }
+        (Value::Mapping(base), value) => (Value::Mapping(base), to_mapping_form(key, value)),
+        (base, Value::Mapping(value)) => (to_mapping_form(key, base), Value::Mapping(value)),
+        (base, value) => (base, value),
+    };
+
+    match (base, value) {
+        (Value::Mapping(base), Value::Mapping(value)) => {
+            Value::Mapping(merge_mappings(base, value))
+        }
+        (Value::Sequence(base), Value::Sequence(value)) if SEQUENCE_KEYS.contains(&key) => {
+            // Mounts of the same target are overridden.
+            let targets = value
+                .iter()
+                .filter_map(|item| mount_target(key, item))
+                .collect::<Vec<_>>();
+
+            let mut merged = base
+                .into_iter()
+                .filter(|item| match mount_target(key, item) {
+                    Some(target) => !targets.contains(&target),
+                    None => true,
+                })
+                .collect::<Vec<_>>();
+
+            for item in value {
+                if !merged.contains(&item) {
+                    merged.push(item);
+                }
+            }
+
+            Value::Sequence(merged)
+        }
+        (_, value) => value,
+    }
+}
+
+/// Converts the short form of a value to its mapping form, if it has one.
+fn to_mapping_form(key: &str, value: Value) -> Value {
+    match (key, value) {
+        ("build", Value::String(context)) => {
+            let mut build = Mapping::new();
+            build.insert("context".into(), Value::String(context));
+            Value::Mapping(build)
+        }
+        ("depends_on", Value::Sequence(services)) => {
+            let mut depends_on = Mapping::new();
+            for service in services {
+                let mut condition = Mapping::new();
+                condition.insert("condition".into(), "service_started".into());
+                depends_on.insert(service, Value::Mapping(condition));
+            }
+            Value::Mapping(depends_on)
+        }
+        (key, Value::Sequence(items)) => {
+            let separator = match MAPPING_KEYS.iter().find(|(name, _)| *name == key) {
+                Some((_, separator)) => *separator,
+                None => return Value::Sequence(items),
+            };
+
+            let mut mapping = Mapping::new();
+            for item in items {
+                match item.as_str().map(|item| item.splitn(2, separator)) {
+                    Some(mut parts) => {
+                        let name = parts.next().unwrap_or_default();
+                        let value = parts.next().map_or(Value::Null, Value::from);
+                        mapping.insert(name.into(), value);
+                    }
+                    None => {
+                        mapping.insert(item, Value::Null);
+                    }
+                }
+            }
+            Value::Mapping(mapping)
+        }
+        (_, value) => value,
+    }
+}
+
+/// The path in the container of a volume or device, which identifies it when
+/// merging.
+fn mount_target(key: &str, item: &Value) -> Option<String> {
+    if key != "volumes" && key != "devices" {
+        return None;
+    }
+
+    match item {
+        // `source:target[:mode]`, or just `target` for anonymous volumes.
+        Value::String(mount) => {
+            let mut parts = mount.split(':');
+            let first = parts.next()?;
+            Some(parts.next().unwrap_or(first).to_owned())
+        }
+        Value::Mapping(mount) => mount
+            .get(&"target".into())
+            .and_then(|target| target.as_str())
+            .map(String::from),
+        _ => None,
+    }
+}
+
+/// Makes the relative paths of a service absolute, by resolving them against
+/// the directory of the file that defined it.
+fn resolve_relative_paths(service: &mut Mapping, directory: &Path) {
+    match service.get_mut(&"build".into()) {
+        Some(Value::String(context)) => resolve_path(context, directory),
+        Some(Value::Mapping(build)) => {
+            if let Some(Value::String(context)) = build.get_mut(&"context".into()) {
+                resolve_path(context, directory);
+            }
+        }
+        _ => (),
+    }
+
+    match service.get_mut(&"env_file".into()) {
+        Some(Value::String(file)) => resolve_path(file, directory),
+        Some(Value::Sequence(files)) => {
+            for file in files {
+                match file {
+                    Value::String(file) => resolve_path(file, directory),
+                    Value::Mapping(file) => {
+                        if let Some(Value::String(file)) = file.get_mut(&"path".into()) {
+                            resolve_path(file, directory);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        _ => (),
+    }
+
+    if let Some(Value::Sequence(volumes)) = service.get_mut(&"volumes".into()) {
+        for volume in volumes {
+            match volume {
+                // Bind mounts start with a path, named volumes with a name.
+                Value::String(volume) if volume.starts_with('.') => {
+                    let (source, rest) = match volume.find(':') {
+                        Some(index) => volume.split_at(index),
+                        None => (volume.as_str(), ""),
+                    };
+                    let mut source = source.to_owned();
+                    resolve_path(&mut source, directory);
+                    *volume = format!("{}{}", source, rest);
+                }
+                Value::Mapping(volume) => {
+                    let is_bind =
+                        volume.get(&"type".into()).and_then(|t| t.as_str()) == Some("bind");
+                    if let (true, Some(Value::String(source))) =
+                        (is_bind, volume.get_mut(&"source".into()))
+                    {
+                        resolve_path(source, directory);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+fn resolve_path(path: &mut String, directory: &Path) {
+    // Remote build contexts aren't paths.
+    if path.contains("://") || path.starts_with("git@") || Path::new(path).is_absolute() {
+        return;
+    }
+
+    let relative = Path::new(path.as_str());
+    let relative = relative.strip_prefix(".").unwrap_or(relative);
+    *path = if relative.as_os_str().is_empty() {
+        directory.display().to_string()
+    } else {
+        directory.join(relative).display().to_string()
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn mapping(yaml: &str) -> Mapping {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// Writes a file below `directory`, creating the directories on the way.
+    fn write_file(directory: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = directory.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn merges_lists_of_mapping_keys() {
+        let base = mapping("environment: [A=1, B=2]\nlabels: [x=1]");
+        let service = mapping("environment: [B=3, C]\nlabels: [y=2]");
+
+        let merged = merge_mappings(base, service);
+
+        assert_eq!(
+            merged,
+            mapping("environment: {A: '1', B: '3', C: null}\nlabels: {x: '1', y: '2'}")
+        );
+    }
+
+    #[test]
+    fn merges_lists_with_mappings() {
+        let base = mapping("extra_hosts: ['db:10.0.0.2']\nsysctls: {net.core.somaxconn: 1024}");
+        let service = mapping("extra_hosts: {cache: 10.0.0.3}\nsysctls: [net.ipv4.ip_forward=1]");
+
+        let merged = merge_mappings(base, service);
+
+        assert_eq!(
+            merged,
+            mapping(
+                "extra_hosts: {db: 10.0.0.2, cache: 10.0.0.3}\n\
+                 sysctls: {net.core.somaxconn: 1024, net.ipv4.ip_forward: '1'}"
+            )
+        );
+    }
+
+    #[test]
+    fn overrides_mounts_of_the_same_target() {
+        let base = mapping("volumes: ['data:/data', './config:/etc/app:ro', /cache]");
+        let service = mapping("volumes: ['other:/data', {type: tmpfs, target: /cache}]");
+
+        let merged = merge_mappings(base, service);
+
+        assert_eq!(
+            merged,
+            mapping(
+                "volumes: ['./config:/etc/app:ro', 'other:/data', {type: tmpfs, target: /cache}]"
+            )
+        );
+    }
+
+    #[test]
+    fn concatenates_sequence_keys_and_overrides_others() {
+        let base = mapping("ports: ['80:80']\ncommand: [a]\nimage: base");
+        let service = mapping("ports: ['443:443', '80:80']\ncommand: [b]");
+
+        let merged = merge_mappings(base, service);
+
+        assert_eq!(
+            merged,
+            mapping("image: base\nports: ['80:80', '443:443']\ncommand: [b]")
+        );
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            "services:\n  a:\n    extends: b\n  b:\n    extends: c\n  c:\n    extends: a\n"
+        )
+        .unwrap();
+
+        let mut document = Document::load(file.path()).unwrap();
+        let err = resolve_extends(&mut document).unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("extends services in a cycle: a -> b -> c -> a"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn rebases_relative_paths_of_other_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = write_file(
+            directory.path(),
+            "docker-compose.yml",
+            "services:\n  \
+               web:\n    \
+                 extends: {file: common/base.yml, service: base}\n    \
+                 volumes: ['./local:/local']\n",
+        );
+        write_file(
+            directory.path(),
+            "common/base.yml",
+            "services:\n  \
+               base:\n    \
+                 build: {context: ./src, dockerfile: Dockerfile}\n    \
+                 env_file: [.env, {path: ./other.env}]\n    \
+                 volumes:\n      \
+                   - ./data:/data:ro\n      \
+                   - named:/named\n      \
+                   - /absolute:/absolute\n      \
+                   - {type: bind, source: ./conf, target: /conf}\n      \
+                   - {type: volume, source: other, target: /other}\n",
+        );
+
+        let mut document = Document::load(&path).unwrap();
+        resolve_extends(&mut document).unwrap();
+
+        let common = directory.path().join("common").display().to_string();
+        let expected = format!(
+            "build: {{context: '{0}/src', dockerfile: Dockerfile}}\n\
+             env_file: ['{0}/.env', {{path: '{0}/other.env'}}]\n\
+             volumes:\n\
+               - '{0}/data:/data:ro'\n\
+               - named:/named\n\
+               - /absolute:/absolute\n\
+               - {{type: bind, source: '{0}/conf', target: /conf}}\n\
+               - {{type: volume, source: other, target: /other}}\n\
+               - ./local:/local\n",
+            common
+        );
+        assert_eq!(
+            document.value["services"]["web"],
+            Value::Mapping(mapping(&expected))
+        );
+    }
+
+    #[test]
+    fn rejects_cycles_across_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = write_file(
+            directory.path(),
+            "docker-compose.yml",
+            "services:\n  web:\n    extends: {file: common/base.yml, service: base}\n",
+        );
+        write_file(
+            directory.path(),
+            "common/base.yml",
+            "services:\n  base:\n    extends: {file: ../docker-compose.yml, service: web}\n",
+        );
+
+        let mut document = Document::load(&path).unwrap();
+        let err = resolve_extends(&mut document).unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("extends services in a cycle: web -> base -> web"),
+            "{}",
+            err
+        );
+    }
+}
@@ -1,3 +1,6 @@
-pub use docker_compose::DockerComposeFrontend;
+pub use docker_compose::{DockerComposeFrontend, HYPHEN_NAMING_TEMPLATE};
 
 mod docker_compose;
+mod extends;
+mod schema;
+mod units;
@@ -0,0 +1,435 @@
+use anyhow::anyhow;
+use jsonschema::{error::ValidationErrorKind, paths::PathChunk, Draft, JSONSchema};
+use serde::de::{
+    self,
+    value::{BorrowedStrDeserializer, MapAccessDeserializer, SeqAccessDeserializer},
+    Deserialize, DeserializeOwned, DeserializeSeed, Deserializer, IntoDeserializer, MapAccess,
+    SeqAccess, Visitor,
+};
+use serde_path_to_error::{Segment, Track};
+use serde_yaml::Value;
+use std::{
+    collections::BTreeMap as Map,
+    fmt::{self, Write},
+    marker::PhantomData,
+    path::Path,
+};
+use yaml_rust::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+use crate::errors::{Error, Result};
+
+/// The compose-spec JSON schema, bundled so that validation works offline.
+const COMPOSE_SPEC: &str = include_str!("compose-spec.json");
+
+/// Appends a mapping key to a YAML path like `services.web`.
+fn push_key(path: &mut String, key: &str) {
+    if !path.is_empty() {
+        path.push('.');
+    }
+    path.push_str(key);
+}
+
+/// Appends a sequence index to a YAML path like `services.web.ports[2]`.
+fn push_index(path: &mut String, index: usize) {
+    write!(path, "[{}]", index).unwrap();
+}
+
+/// The line and column of every key and sequence item in a YAML document,
+/// by their YAML paths.
+pub struct SourceMap {
+    positions: Map<String, (usize, usize)>,
+}
+
+enum Frame {
+    Mapping { path: String, key: Option<String> },
+    Sequence { path: String, index: usize },
+}
+
+struct SourceMapReceiver {
+    stack: Vec<Frame>,
+    positions: Map<String, (usize, usize)>,
+}
+
+impl MarkedEventReceiver for SourceMapReceiver {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let position = (mark.line(), mark.col() + 1);
+
+        let path = match event {
+            Event::Scalar(..)
+            | Event::Alias(_)
+            | Event::MappingStart(_)
+            | Event::SequenceStart(_) => match self.stack.last_mut() {
+                None => String::new(),
+                Some(Frame::Mapping { path, key }) => match key.take() {
+                    // A value, its position is the position of its key.
+                    Some(key) => {
+                        let mut path = path.clone();
+                        push_key(&mut path, &key);
+                        path
+                    }
+                    None => {
+                        if let Event::Scalar(ref value, ..) = event {
+                            let mut key_path = path.clone();
+                            push_key(&mut key_path, value);
+                            self.positions.entry(key_path).or_insert(position);
+                            *key = Some(value.clone());
+                            return;
+                        }
+                        // Complex keys can't be part of a path.
+                        *key = Some("?".into());
+                        String::from("?")
+                    }
+                },
+                Some(Frame::Sequence { path, index }) => {
+                    let mut path = path.clone();
+                    push_index(&mut path, *index);
+                    *index += 1;
+                    path
+                }
+            },
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+                return;
+            }
+            _ => return,
+        };
+
+        self.positions.entry(path.clone()).or_insert(position);
+
+        match event {
+            Event::MappingStart(_) => self.stack.push(Frame::Mapping { path, key: None }),
+            Event::SequenceStart(_) => self.stack.push(Frame::Sequence { path, index: 0 }),
+            _ => (),
+        }
+    }
+}
+
+impl SourceMap {
+    pub fn new(source: &str) -> SourceMap {
+        let mut receiver = SourceMapReceiver {
+            stack: Vec::new(),
+            positions: Map::new(),
+        };
+
+        // Syntax errors are reported by serde_yaml, with their location.
+        let _ = Parser::new(source.chars()).load(&mut receiver, false);
+
+        SourceMap {
+            positions: receiver.positions,
+        }
+    }
+
+    /// Returns the line and column of a path, or of its closest parent if the
+    /// path itself isn't in the document.
+    pub fn position(&self, path: &str) -> Option<(usize, usize)> {
+        let mut path = path;
+        loop {
+            if let Some(position) = self.positions.get(path) {
+                return Some(*position);
+            }
+
+            path = &path[..path.rfind(&['.', '['][..])?];
+        }
+    }
+
+    /// Prints a warning, located at a path.
+    pub fn warn(&self, file: &Path, path: &str, message: &str) {
+        match self.position(path) {
+            Some((line, column)) => eprintln!(
+                "WARNING: {}:{}:{}: {}",
+                file.display(),
+                line,
+                column,
+                message
+            ),
+            None => eprintln!("WARNING: {}: {}", file.display(), message),
+        }
+    }
+
+    pub fn error(&self, file: &Path, path: &str, message: &str) -> Error {
+        let position = self.position(path);
+        Error::Parse {
+            file: file.into(),
+            line: position.map(|(line, _)| line),
+            column: position.map(|(_, column)| column),
+            message: format!("{}: {}", path, message),
+        }
+    }
+}
+
+/// Deserializes a YAML document, collecting the YAML paths of all fields that
+/// were ignored along the way. Errors come with the YAML path of the value
+/// that couldn't be deserialized, since the document may not be the one the
+/// user wrote.
+pub fn from_str_reporting_ignored<T>(
+    source: &str,
+    ignored: &mut Vec<String>,
+) -> Result<T, (String, serde_yaml::Error)>
+where
+    T: DeserializeOwned,
+{
+    struct Seed<'a, T> {
+        ignored: &'a mut Vec<String>,
+        track: &'a mut Track,
+        value: PhantomData<T>,
+    }
+
+    impl<'de, 'a, T: Deserialize<'de>> DeserializeSeed<'de> for Seed<'a, T> {
+        type Value = T;
+
+        fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<T, D::Error> {
+            let ignored = self.ignored;
+            let mut callback = |path: serde_ignored::Path| ignored.push(ignored_path(&path));
+            let deserializer = serde_ignored::Deserializer::new(deserializer, &mut callback);
+            T::deserialize(serde_path_to_error::Deserializer::new(
+                deserializer,
+                self.track,
+            ))
+        }
+    }
+
+    let mut track = Track::new();
+    let seed = Seed {
+        ignored,
+        track: &mut track,
+        value: PhantomData,
+    };
+
+    serde_yaml::seed::from_str_seed(source, seed).map_err(|err| (error_path(&track.path()), err))
+}
+
+/// Deserializes a value that has a short form, and a long form that is
+/// written as a mapping, like `ports` or `build`. Unlike
+/// `#[serde(untagged)]`, the mapping isn't buffered first, so keys that the
+/// long form doesn't know still reach `from_str_reporting_ignored`.
+pub fn short_or_long<'de, D, S, L, T>(
+    deserializer: D,
+    short: fn(S) -> T,
+    long: fn(L) -> T,
+) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    S: Deserialize<'de>,
+    L: Deserialize<'de>,
+{
+    struct ShortOrLong<S, L, T> {
+        short: fn(S) -> T,
+        long: fn(L) -> T,
+    }
+
+    impl<'de, S, L, T> Visitor<'de> for ShortOrLong<S, L, T>
+    where
+        S: Deserialize<'de>,
+        L: Deserialize<'de>,
+    {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a short form or a mapping")
+        }
+
+        fn visit_bool<E: de::Error>(self, v: bool) -> Result<T, E> {
+            S::deserialize(v.into_deserializer()).map(self.short)
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<T, E> {
+            S::deserialize(v.into_deserializer()).map(self.short)
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<T, E> {
+            S::deserialize(v.into_deserializer()).map(self.short)
+        }
+
+        fn visit_f64<E: de::Error>(self, v: f64) -> Result<T, E> {
+            S::deserialize(v.into_deserializer()).map(self.short)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<T, E> {
+            S::deserialize(v.to_owned().into_deserializer()).map(self.short)
+        }
+
+        fn visit_borrowed_str<E: de::Error>(self, v: &'de str) -> Result<T, E> {
+            S::deserialize(BorrowedStrDeserializer::new(v)).map(self.short)
+        }
+
+        fn visit_string<E: de::Error>(self, v: String) -> Result<T, E> {
+            S::deserialize(v.into_deserializer()).map(self.short)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<T, A::Error> {
+            S::deserialize(SeqAccessDeserializer::new(seq)).map(self.short)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<T, A::Error> {
+            L::deserialize(MapAccessDeserializer::new(map)).map(self.long)
+        }
+    }
+
+    deserializer.deserialize_any(ShortOrLong { short, long })
+}
+
+/// Formats the path of a value that failed to deserialize as a YAML path.
+fn error_path(path: &serde_path_to_error::Path) -> String {
+    let mut yaml_path = String::new();
+    for segment in path.iter() {
+        match segment {
+            Segment::Seq { index } => push_index(&mut yaml_path, *index),
+            Segment::Map { key } => push_key(&mut yaml_path, key),
+            Segment::Enum { .. } | Segment::Unknown => (),
+        }
+    }
+    yaml_path
+}
+
+/// Formats the path of a field ignored while deserializing as a YAML path.
+fn ignored_path(path: &serde_ignored::Path) -> String {
+    match path {
+        serde_ignored::Path::Root => String::new(),
+        serde_ignored::Path::Seq { parent, index } => {
+            let mut path = ignored_path(parent);
+            push_index(&mut path, *index);
+            path
+        }
+        serde_ignored::Path::Map { parent, key } => {
+            let mut path = ignored_path(parent);
+            push_key(&mut path, key);
+            path
+        }
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => ignored_path(parent),
+    }
+}
+
+/// Applies the YAML merge keys (`<<`) of a document, which serde_yaml keeps as
+/// regular keys. The keys of a mapping take precedence over merged keys, and
+/// mappings earlier in a list of merged mappings take precedence over later
+/// ones. Returns the path of the first invalid merge key on failure.
+pub fn apply_merge_keys(value: &mut Value, path: &str) -> Result<(), String> {
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                let mut path = path.to_owned();
+                push_key(&mut path, key.as_str().unwrap_or("?"));
+                apply_merge_keys(value, &path)?;
+            }
+
+            let merged = match mapping.remove(&Value::from("<<")) {
+                Some(Value::Mapping(merged)) => vec![merged],
+                Some(Value::Sequence(merged)) => merged
+                    .into_iter()
+                    .map(|merged| match merged {
+                        Value::Mapping(merged) => Ok(merged),
+                        _ => Err(()),
+                    })
+                    .collect::<Result<_, _>>()
+                    .map_err(|()| merge_key_path(path))?,
+                Some(_) => return Err(merge_key_path(path)),
+                None => return Ok(()),
+            };
+
+            for merged in merged {
+                for (key, value) in merged {
+                    if !mapping.contains_key(&key) {
+                        mapping.insert(key, value);
+                    }
+                }
+            }
+        }
+        Value::Sequence(sequence) => {
+            for (index, value) in sequence.iter_mut().enumerate() {
+                let mut path = path.to_owned();
+                push_index(&mut path, index);
+                apply_merge_keys(value, &path)?;
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+fn merge_key_path(path: &str) -> String {
+    let mut path = path.to_owned();
+    push_key(&mut path, "<<");
+    path
+}
+
+/// Validates a compose file against the compose specification. All
+/// validation errors are reported, in the order they appear in the file.
+pub fn validate(file: &Path, source_map: &SourceMap, document: &Value) -> Result<()> {
+    let schema: serde_json::Value = serde_json::from_str(COMPOSE_SPEC)?;
+    let schema = JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema)
+        .map_err(|err| anyhow!("the bundled compose schema is invalid: {}", err))?;
+
+    let document = serde_json::to_value(document)
+        .map_err(|err| source_map.error(file, "", &err.to_string()))?;
+
+    let errors = match schema.validate(&document) {
+        Ok(()) => return Ok(()),
+        Err(errors) => errors,
+    };
+
+    let mut messages = Vec::new();
+    for err in errors {
+        let mut path = String::new();
+        for chunk in err.instance_path.iter() {
+            match chunk {
+                PathChunk::Property(key) => push_key(&mut path, key),
+                PathChunk::Index(index) => push_index(&mut path, *index),
+                PathChunk::Keyword(_) => (),
+            }
+        }
+
+        match err.kind {
+            // Point at the unexpected keys themselves.
+            ValidationErrorKind::AdditionalProperties { ref unexpected } => {
+                for key in unexpected {
+                    let mut path = path.clone();
+                    push_key(&mut path, key);
+                    messages.push((path, "unknown key".to_owned()));
+                }
+            }
+            _ => messages.push((path, err.to_string())),
+        }
+    }
+
+    let mut errors = messages
+        .into_iter()
+        .map(|(path, message)| (source_map.position(&path), path, message))
+        .collect::<Vec<_>>();
+    errors.sort();
+    errors.dedup();
+
+    let (position, path, message) = errors.remove(0);
+
+    // The first error decides the location, the rest are listed after it.
+    let mut message = format!("{}: {}", path, message);
+    for (position, path, other_message) in errors {
+        match position {
+            Some((line, column)) => write!(
+                message,
+                "\n{}:{}:{}: {}: {}",
+                file.display(),
+                line,
+                column,
+                path,
+                other_message
+            ),
+            None => write!(message, "\n{}: {}: {}", file.display(), path, other_message),
+        }
+        .unwrap();
+    }
+
+    Err(Error::Parse {
+        file: file.into(),
+        line: position.map(|(line, _)| line),
+        column: position.map(|(_, column)| column),
+        message,
+    })
+}
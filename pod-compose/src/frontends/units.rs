@@ -0,0 +1,137 @@
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+
+/// Parses a duration in the format used by compose files, which is the same
+/// as the one used by Go: `1h30m`, `10s`, `1.5s` or `500ms`.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    if value == "0" {
+        return Ok(Duration::from_secs(0));
+    }
+
+    if value.is_empty() {
+        return Err(anyhow!("invalid duration: {:?}", value));
+    }
+
+    let mut duration = Duration::from_secs(0);
+    let mut rest = value;
+
+    while !rest.is_empty() {
+        let number_end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(number_end);
+
+        let unit_end = tail
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_end);
+
+        let number: f64 = number
+            .parse()
+            .map_err(|_| anyhow!("invalid duration: {:?}", value))?;
+
+        let seconds = match unit {
+            "ns" => 1e-9,
+            "us" | "µs" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return Err(anyhow!("invalid unit in duration: {:?}", value)),
+        };
+
+        duration = Duration::try_from_secs_f64(number * seconds)
+            .ok()
+            .and_then(|part| duration.checked_add(part))
+            .ok_or_else(|| anyhow!("duration is out of range: {:?}", value))?;
+        rest = tail;
+    }
+
+    Ok(duration)
+}
+
+/// Parses a byte value in the format used by compose files, such as `512m`,
+/// `1gb` or `1.5g`. Units are powers of 1024 and a value without a unit is
+/// in bytes.
+pub fn parse_bytes(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_start);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("invalid byte value: {:?}", value))?;
+
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        "t" | "tb" => 1 << 40,
+        _ => return Err(anyhow!("invalid unit in byte value: {:?}", value)),
+    };
+
+    let bytes = number * multiplier as f64;
+    if bytes >= u64::MAX as f64 {
+        return Err(anyhow!("byte value is out of range: {:?}", value));
+    }
+
+    Ok(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("0").unwrap(), Duration::from_secs(0));
+        assert_eq!(parse_duration("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("20us").unwrap(), Duration::from_micros(20));
+        assert_eq!(parse_duration("20µs").unwrap(), Duration::from_micros(20));
+        assert_eq!(
+            parse_duration("1h30m").unwrap(),
+            Duration::from_secs(90 * 60)
+        );
+        assert_eq!(parse_duration(" 1m30s ").unwrap(), Duration::from_secs(90));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("10d").is_err());
+        assert!(parse_duration("1.2.3s").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_out_of_range() {
+        assert!(parse_duration("99999999999999999999h").is_err());
+        assert!(parse_duration("5000000000000000h5000000000000000h").is_err());
+    }
+
+    #[test]
+    fn parses_bytes() {
+        assert_eq!(parse_bytes("1024").unwrap(), 1024);
+        assert_eq!(parse_bytes("100b").unwrap(), 100);
+        assert_eq!(parse_bytes("1k").unwrap(), 1 << 10);
+        assert_eq!(parse_bytes("512m").unwrap(), 512 << 20);
+        assert_eq!(parse_bytes("1gb").unwrap(), 1 << 30);
+        assert_eq!(parse_bytes("1.5g").unwrap(), 3 << 29);
+        assert_eq!(parse_bytes("2T").unwrap(), 2 << 40);
+    }
+
+    #[test]
+    fn rejects_invalid_bytes() {
+        assert!(parse_bytes("").is_err());
+        assert!(parse_bytes("m").is_err());
+        assert!(parse_bytes("10x").is_err());
+        assert!(parse_bytes("99999999999999999999t").is_err());
+    }
+}
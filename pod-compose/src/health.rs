@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use crate::models::{HealthStatus, Healthcheck};
+
+/// How often a healthcheck is run while waiting for a container to become
+/// healthy. Failures are still only counted once per healthcheck interval.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps track of the results of repeatedly running a container's
+/// healthcheck, and decides when the container should be considered
+/// unhealthy using the same rules as podman.
+pub struct HealthProbe {
+    started: Instant,
+    last_failure: Option<Instant>,
+    failures: u64,
+}
+
+impl HealthProbe {
+    pub fn new() -> HealthProbe {
+        HealthProbe {
+            started: Instant::now(),
+            last_failure: None,
+            failures: 0,
+        }
+    }
+
+    /// Records the result of running the healthcheck once. Returns true once
+    /// the container has failed the healthcheck enough times to be
+    /// considered unhealthy.
+    pub fn record(&mut self, healthcheck: &Healthcheck, status: HealthStatus) -> bool {
+        if status == HealthStatus::Healthy {
+            self.last_failure = None;
+            self.failures = 0;
+            return false;
+        }
+
+        // Failures during the start period don't count.
+        let start_period = healthcheck.start_period.unwrap_or_default();
+        if self.started.elapsed() < start_period {
+            return false;
+        }
+
+        let interval = healthcheck
+            .interval
+            .unwrap_or(Healthcheck::DEFAULT_INTERVAL);
+
+        let now = Instant::now();
+        let count_failure = self
+            .last_failure
+            .map(|last_failure| now.duration_since(last_failure) >= interval)
+            .unwrap_or(true);
+
+        if count_failure {
+            self.last_failure = Some(now);
+            self.failures += 1;
+        }
+
+        let retries = healthcheck.retries.unwrap_or(Healthcheck::DEFAULT_RETRIES);
+        self.failures >= retries
+    }
+}
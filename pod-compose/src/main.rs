@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Result};
+use anyhow::anyhow;
 use crossterm::{
     cursor,
     style::{self, Colorize, Styler},
@@ -7,21 +7,28 @@ use crossterm::{
 use log::info;
 use std::{
     env,
-    io::{stdout, Write},
+    io::{stderr, stdout, Write},
     path::{Path, PathBuf},
+    process,
+    time::Duration,
 };
 use structopt::StructOpt;
 
 use backends::PodmanBackend;
-use controller::{ContainerOperation, Controller};
-use frontends::DockerComposeFrontend;
-use models::{BuildPolicy, ContainerName, PullPolicy};
+use controller::{
+    ContainerOperation, ContainerTarget, Controller, OperationOutcome, ServiceStatus,
+};
+use errors::{Error, Result};
+use frontends::{DockerComposeFrontend, HYPHEN_NAMING_TEMPLATE};
+use models::{BuildPolicy, PullOptions, PullPolicy};
 use services::ComposerFrontend;
 
 mod backends;
 mod controller;
+mod errors;
 mod frontends;
 mod hasher;
+mod health;
 mod models;
 mod services;
 
@@ -30,10 +37,58 @@ mod services;
     name = "pod-compose",
     about = "A docker-compose compatible tool for running containers with podman."
 )]
-enum Opt {
+struct Opt {
+    #[structopt(long = "profile", number_of_values = 1)]
+    /// Enable services of a profile, can be given more than once. Defaults to
+    /// the comma separated profiles in COMPOSE_PROFILES.
+    profiles: Vec<String>,
+
+    #[structopt(long, conflicts_with = "hyphen-names")]
+    /// Template for container names, with {project}, {service}, {index}
+    /// (starting at 0) and {number} (starting at 1). Defaults to
+    /// {project}_{service}_{index}.
+    naming_template: Option<String>,
+
+    #[structopt(long)]
+    /// Name containers like docker-compose v2, project-service-number.
+    hyphen_names: bool,
+
+    #[structopt(long)]
+    /// Recognize containers created by docker-compose or podman-compose as
+    /// part of the project.
+    label_compat: bool,
+
+    #[structopt(long)]
+    /// Don't write the labels of docker-compose on created containers.
+    no_compose_labels: bool,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Replaces the containers created by docker-compose or podman-compose
+    /// with containers managed by pod-compose.
+    Adopt {
+        #[structopt(long, default_value = "5")]
+        timeout: u32,
+
+        #[structopt(name = "SERVICE")]
+        /// Services to adopt, all of them if none are given.
+        services: Vec<String>,
+    },
     Build {
         #[structopt(short, long)]
         pull: bool,
+
+        #[structopt(long)]
+        /// Don't include the services that the given services depend on.
+        no_deps: bool,
+
+        #[structopt(name = "SERVICE")]
+        /// Services to build, all of them if none are given.
+        services: Vec<String>,
     },
     Down {
         #[structopt(short, long)]
@@ -45,6 +100,39 @@ enum Opt {
 
         #[structopt(long)]
         remove_orphans: bool,
+
+        #[structopt(name = "SERVICE")]
+        /// Services to stop and remove, all of them if none are given.
+        services: Vec<String>,
+    },
+    /// Pulls the images of services that aren't built.
+    Pull {
+        #[structopt(long)]
+        /// Warn about images that couldn't be pulled instead of failing.
+        ignore_pull_failures: bool,
+
+        #[structopt(short, long)]
+        /// Don't print the progress of pulls.
+        quiet: bool,
+
+        #[structopt(long)]
+        /// Also pull the images of the services that the given services
+        /// depend on.
+        include_deps: bool,
+
+        #[structopt(name = "SERVICE")]
+        /// Services to pull, all of them if none are given.
+        services: Vec<String>,
+    },
+    /// Pushes the images of services that are built and named by `image`.
+    Push {
+        #[structopt(short, long)]
+        /// Don't print the progress of pushes.
+        quiet: bool,
+
+        #[structopt(name = "SERVICE")]
+        /// Services to push, all of them if none are given.
+        services: Vec<String>,
     },
     /// Finds a docker-compose.yaml file and starts the containers defined in it.
     Up {
@@ -56,11 +144,40 @@ enum Opt {
         /// Build images before starting the containers.
         build: bool,
 
+        #[structopt(long)]
+        /// Wait for services to be running, or healthy if they have a healthcheck.
+        wait: bool,
+
+        #[structopt(long)]
+        /// Maximum number of seconds to wait with --wait.
+        wait_timeout: Option<u64>,
+
         #[structopt(long, default_value = "5")]
         timeout: u32,
 
         #[structopt(long)]
         remove_orphans: bool,
+
+        #[structopt(long)]
+        /// Don't include the services that the given services depend on.
+        no_deps: bool,
+
+        #[structopt(
+            long,
+            possible_values = &["always", "missing", "never"],
+            parse(try_from_str = parse_pull_policy)
+        )]
+        /// Pull images always, only if they're missing or never, instead of
+        /// following the pull_policy of each service.
+        pull: Option<PullPolicy>,
+
+        #[structopt(long, number_of_values = 1, parse(try_from_str = parse_scale))]
+        /// Scale a service to a number of replicas, in the form SERVICE=NUM.
+        scale: Vec<(String, u64)>,
+
+        #[structopt(name = "SERVICE")]
+        /// Services to start, all of them if none are given.
+        services: Vec<String>,
     },
     Stop {
         #[structopt(long, default_value = "5")]
@@ -68,9 +185,49 @@ enum Opt {
 
         #[structopt(long)]
         remove_orphans: bool,
+
+        #[structopt(name = "SERVICE")]
+        /// Services to stop, all of them if none are given.
+        services: Vec<String>,
     },
 }
 
+impl Command {
+    /// The services named on the command line.
+    fn services(&self) -> &[String] {
+        match self {
+            Command::Adopt { services, .. }
+            | Command::Build { services, .. }
+            | Command::Down { services, .. }
+            | Command::Pull { services, .. }
+            | Command::Push { services, .. }
+            | Command::Up { services, .. }
+            | Command::Stop { services, .. } => services,
+        }
+    }
+}
+
+fn parse_scale(scale: &str) -> anyhow::Result<(String, u64)> {
+    let index = scale
+        .find('=')
+        .ok_or_else(|| anyhow!("expected SERVICE=NUM, got {:?}", scale))?;
+    let replicas = scale[index + 1..].parse()?;
+
+    Ok((scale[..index].to_owned(), replicas))
+}
+
+fn parse_pull_policy(pull_policy: &str) -> anyhow::Result<PullPolicy> {
+    match pull_policy {
+        "always" => Ok(PullPolicy::Always),
+        "missing" => Ok(PullPolicy::IfNotPresent),
+        "never" => Ok(PullPolicy::Never),
+        _ => Err(anyhow!(
+            "expected always, missing or never, got {:?}",
+            pull_policy
+        )),
+    }
+}
+
 fn find_compose_file<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
     for path in path.as_ref().ancestors() {
         let docker_file_path = path.join("docker-compose.yml");
@@ -87,11 +244,39 @@ fn find_compose_file<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
     None
 }
 
-fn main() -> Result<()> {
+fn main() {
     pretty_env_logger::init_custom_env("LOG");
 
     let opt = Opt::from_args();
 
+    if let Err(err) = run(opt) {
+        // Failing to print the error leaves nothing else to report.
+        let _ = print_error(&err);
+        process::exit(err.exit_code());
+    }
+}
+
+fn print_error(err: &Error) -> Result<()> {
+    let mut stderr = stderr();
+
+    if let Error::BuildFailed { log_tail, .. } = err.kind() {
+        if !log_tail.is_empty() {
+            stderr.queue(style::Print("Last lines of the build output:\n"))?;
+            for line in log_tail {
+                stderr.queue(style::Print(format!("    {}\n", line)))?;
+            }
+        }
+    }
+
+    stderr
+        .queue(style::PrintStyledContent("ERROR: ".red().bold()))?
+        .queue(style::Print(format!("{}\n", err)))?
+        .flush()?;
+
+    Ok(())
+}
+
+fn run(opt: Opt) -> Result<()> {
     let mut stdout = stdout();
 
     let current_dir = env::current_dir()?;
@@ -114,7 +299,33 @@ fn main() -> Result<()> {
         .ok_or_else(|| anyhow!("Couldn't determine the project name."))?;
     info!("project name {:?}", project_name);
 
-    let mut frontend = DockerComposeFrontend::new();
+    let profiles = if opt.profiles.is_empty() {
+        env::var("COMPOSE_PROFILES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|profile| profile.trim().to_owned())
+            .filter(|profile| !profile.is_empty())
+            .collect()
+    } else {
+        opt.profiles
+    };
+
+    let scale = match opt.command {
+        Command::Up { ref scale, .. } => scale.clone(),
+        _ => Vec::new(),
+    };
+
+    // Like in docker-compose, naming a service enables it whatever its
+    // profiles are.
+    let mut frontend = DockerComposeFrontend::new()
+        .profiles(profiles)
+        .enabled_services(opt.command.services().to_vec())
+        .scale(scale);
+    if opt.hyphen_names {
+        frontend = frontend.naming_template(HYPHEN_NAMING_TEMPLATE);
+    } else if let Some(naming_template) = opt.naming_template {
+        frontend = frontend.naming_template(naming_template);
+    }
     let composition = frontend.composition(project_name, compose_file_path.as_path())?;
     info!("parsed composition");
 
@@ -124,35 +335,71 @@ fn main() -> Result<()> {
     let mut controller = Controller::init(project_name, backend, composition)?;
     info!("created controller");
 
-    match opt {
-        Opt::Build { pull } => {
-            let pull_policy = if pull {
-                PullPolicy::Always
+    if !opt.no_compose_labels {
+        controller.write_compose_labels(work_directory, &[compose_file_path.as_path()]);
+    }
+
+    if opt.label_compat || matches!(opt.command, Command::Adopt { .. }) {
+        controller.recognize_foreign_labels()?;
+    }
+
+    match opt.command {
+        Command::Adopt { timeout, services } => {
+            controller.select_services(&services, false)?;
+
+            controller.pull_images(None, Default::default())?;
+            controller.build_images(BuildPolicy::IfChanged, None)?;
+
+            let diff = controller.adopt_containers_diff()?;
+            if diff.is_empty() {
+                info!("found no containers to adopt");
             } else {
-                PullPolicy::IfNotPresent
-            };
+                container_apply(&mut controller, &mut stdout, diff, timeout)?;
+            }
+        }
+        Command::Build {
+            pull,
+            no_deps,
+            services,
+        } => {
+            controller.select_services(&services, !no_deps)?;
+
+            let pull_policy = if pull { Some(PullPolicy::Always) } else { None };
 
             controller.build_images(BuildPolicy::Always, pull_policy)?;
         }
-        Opt::Down {
+        Command::Down {
             volumes: _,
             timeout,
             remove_orphans,
+            services,
         } => {
+            controller.select_services(&services, false)?;
             check_orphans(&mut controller, &mut stdout, remove_orphans, timeout)?;
 
             let diff = controller.remove_containers_diff()?;
             container_apply(&mut controller, &mut stdout, diff, timeout)?;
+
+            for network_name in controller.remove_networks()? {
+                println!("Removed network {}", network_name.0);
+            }
         }
-        Opt::Up {
+        Command::Up {
             detach: _,
             build,
+            wait,
+            wait_timeout,
             timeout,
             remove_orphans,
+            no_deps,
+            pull,
+            scale: _,
+            services,
         } => {
+            controller.select_services(&services, !no_deps)?;
             check_orphans(&mut controller, &mut stdout, remove_orphans, timeout)?;
 
-            controller.pull_images(PullPolicy::IfNotPresent)?;
+            controller.pull_images(pull, Default::default())?;
 
             let build_policy = if build {
                 BuildPolicy::Always
@@ -160,15 +407,49 @@ fn main() -> Result<()> {
                 BuildPolicy::IfChanged
             };
 
-            controller.build_images(build_policy, PullPolicy::IfNotPresent)?;
+            controller.build_images(build_policy, pull)?;
+
+            for network_name in controller.create_networks()? {
+                println!("Created network {}", network_name.0);
+            }
 
             let diff = controller.start_containers_diff()?;
             container_apply(&mut controller, &mut stdout, diff, timeout)?;
+
+            let diff = controller.host_entries_diff()?;
+            if !diff.is_empty() {
+                container_apply(&mut controller, &mut stdout, diff, timeout)?;
+            }
+
+            if wait {
+                let wait_timeout = wait_timeout.map(Duration::from_secs);
+                wait_services(&mut controller, &mut stdout, wait_timeout)?;
+            }
         }
-        Opt::Stop {
+        Command::Pull {
+            ignore_pull_failures,
+            quiet,
+            include_deps,
+            services,
+        } => {
+            controller.select_services(&services, include_deps)?;
+
+            let options = PullOptions {
+                quiet,
+                ignore_failures: ignore_pull_failures,
+            };
+            controller.pull_images(Some(PullPolicy::Always), options)?;
+        }
+        Command::Push { quiet, services } => {
+            controller.select_services(&services, false)?;
+            controller.push_images(quiet)?;
+        }
+        Command::Stop {
             timeout,
             remove_orphans,
+            services,
         } => {
+            controller.select_services(&services, false)?;
             check_orphans(&mut controller, &mut stdout, remove_orphans, timeout)?;
 
             let diff = controller.stop_containers_diff()?;
@@ -193,7 +474,7 @@ fn check_orphans(
         if remove_orphans {
             let diff = orphans
                 .into_iter()
-                .map(|name| (name, ContainerOperation::Remove))
+                .map(|id| (ContainerTarget::Container(id), ContainerOperation::Remove))
                 .collect();
             container_apply(controller, stdout, diff, timeout)?;
         } else {
@@ -214,7 +495,7 @@ fn check_orphans(
 fn container_apply(
     controller: &mut Controller,
     stdout: &mut impl Write,
-    operations: Vec<(ContainerName, ContainerOperation)>,
+    operations: Vec<(ContainerTarget, ContainerOperation)>,
     timeout: u32,
 ) -> Result<()> {
     fn operation_verb(operation: ContainerOperation) -> &'static str {
@@ -229,9 +510,9 @@ fn container_apply(
 
     let lines = operations
         .iter()
-        .map(|(container_name, operation)| {
+        .map(|(target, operation)| {
             let verb = operation_verb(*operation);
-            format!("{} {}", verb, container_name.0)
+            format!("{} {}", verb, controller.target_name(target))
         })
         .collect::<Vec<_>>();
 
@@ -248,17 +529,117 @@ fn container_apply(
 
     stdout.flush()?;
 
-    for (line, (container_name, operation)) in operations.into_iter().enumerate() {
-        controller.container_apply(&container_name, operation, timeout)?;
+    let mut failures = Vec::new();
+
+    controller.apply(&operations, timeout, |line, outcome| {
+        let status = match outcome {
+            OperationOutcome::Done => "done".green().bold(),
+            OperationOutcome::Skipped => "skipped".yellow().bold(),
+            OperationOutcome::RolledBack => "rolled back".yellow().bold(),
+            OperationOutcome::Failed(err) => {
+                failures.push((line, err));
+                "error".red().bold()
+            }
+        };
 
         stdout
             .queue(cursor::SavePosition)?
             .queue(cursor::MoveToPreviousLine((lines.len() - line) as u16))?
             .queue(cursor::MoveRight(longest_line as u16 + 5))?
-            .queue(style::PrintStyledContent("done".green().bold()))?
+            .queue(style::PrintStyledContent(status))?
             .queue(cursor::RestorePosition)?
             .flush()?;
+
+        Ok(())
+    })?;
+
+    if failures.is_empty() {
+        return Ok(());
     }
 
-    Ok(())
+    for (line, err) in failures.iter() {
+        stdout
+            .queue(style::PrintStyledContent("ERROR: ".red().bold()))?
+            .queue(style::Print(format!("{}: {}\n", lines[*line], err)))?;
+    }
+    stdout.flush()?;
+
+    // The first failure decides the exit code.
+    let count = failures.len();
+    let (line, err) = failures.remove(0);
+    Err(err.context(&lines[line]).context(format!(
+        "{} of {} container operations failed",
+        count,
+        lines.len()
+    )))
+}
+
+fn wait_services(
+    controller: &mut Controller,
+    stdout: &mut impl Write,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let service_names = controller.service_names();
+
+    let lines = service_names
+        .iter()
+        .map(|service_name| format!("Waiting for {}", service_name))
+        .collect::<Vec<_>>();
+
+    let longest_line = lines.iter().map(|line| line.len()).max().unwrap_or(0);
+
+    for line in lines.iter() {
+        stdout.queue(style::Print(line))?;
+
+        let padding = longest_line - line.len() + 1;
+        stdout
+            .queue(cursor::MoveRight(padding as u16))?
+            .queue(style::Print("...\n"))?;
+    }
+
+    stdout.flush()?;
+
+    let mut failures = 0;
+    let mut first_failure = None;
+
+    controller.wait_services(timeout, |service_name, status| {
+        let line = service_names
+            .iter()
+            .position(|name| name == service_name)
+            .ok_or_else(|| anyhow!("unknown service: {:?}", service_name))?;
+
+        if status != ServiceStatus::Running && status != ServiceStatus::Healthy {
+            failures += 1;
+            first_failure.get_or_insert(status);
+        }
+
+        let status = match status {
+            ServiceStatus::Running => "running".green().bold(),
+            ServiceStatus::Healthy => "healthy".green().bold(),
+            ServiceStatus::Unhealthy => "unhealthy".red().bold(),
+            ServiceStatus::Exited => "exited".red().bold(),
+            ServiceStatus::TimedOut => "timed out".red().bold(),
+        };
+
+        stdout
+            .queue(cursor::SavePosition)?
+            .queue(cursor::MoveToPreviousLine((lines.len() - line) as u16))?
+            .queue(cursor::MoveRight(longest_line as u16 + 5))?
+            .queue(style::PrintStyledContent(status))?
+            .queue(cursor::RestorePosition)?
+            .flush()?;
+
+        Ok(())
+    })?;
+
+    // The first failure decides the exit code.
+    match first_failure {
+        None => Ok(()),
+        Some(ServiceStatus::TimedOut) => Err(Error::Timeout(format!(
+            "{} of {} services are not running, the first one did not start in time",
+            failures,
+            lines.len()
+        ))),
+        Some(_) => Err(anyhow!("{} of {} services are not running", failures, lines.len()).into()),
+    }
 }
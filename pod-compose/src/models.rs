@@ -1,10 +1,87 @@
-use std::{collections::BTreeMap as Map, path::PathBuf};
+use std::{
+    collections::{BTreeMap as Map, BTreeSet as Set},
+    path::PathBuf,
+    time::Duration,
+};
 
 #[derive(Clone, Debug, Default)]
 pub struct Composition {
     pub build_images: Vec<ImageBuildSpec>,
     pub pull_images: Vec<ImagePullSpec>,
+    /// Built images that are named by the compose file, and can be pushed.
+    pub push_images: Vec<ImageName>,
+    pub services: Map<String, ServiceSpec>,
+    pub networks: Vec<NetworkSpec>,
     pub containers: Vec<ContainerSpec>,
+    /// Services that are defined, but disabled by their profiles. Their
+    /// containers aren't orphans, and are removed along with the others.
+    pub disabled_services: Set<String>,
+}
+
+/// Settings that apply to all replicas of a service, but that don't affect
+/// the containers themselves. Changing these never recreates a container.
+#[derive(Clone, Debug, Default)]
+pub struct ServiceSpec {
+    pub update_config: UpdateConfig,
+    /// Services that have to be started before this one.
+    pub depends_on: Set<String>,
+    /// Links to other services, keyed by alias.
+    pub links: Map<String, String>,
+    /// How the image of the service is pulled, unless the command says
+    /// otherwise.
+    pub pull_policy: Option<PullPolicy>,
+    /// How the image of the service is built, unless the command says to
+    /// always build.
+    pub build_policy: Option<BuildPolicy>,
+}
+
+impl ServiceSpec {
+    /// All services this service depends on, either directly or via links.
+    pub fn dependencies(&self) -> Set<String> {
+        self.depends_on
+            .iter()
+            .chain(self.links.values())
+            .cloned()
+            .collect()
+    }
+}
+
+/// Describes how the replicas of a service are recreated when it changes.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct UpdateConfig {
+    /// The number of replicas updated in each batch, 0 updates all at once.
+    pub parallelism: u64,
+    pub delay: Duration,
+    pub failure_action: UpdateFailureAction,
+    pub order: UpdateOrder,
+}
+
+impl Default for UpdateConfig {
+    fn default() -> Self {
+        UpdateConfig {
+            parallelism: 1,
+            delay: Duration::from_secs(0),
+            failure_action: UpdateFailureAction::Pause,
+            order: UpdateOrder::StartFirst,
+        }
+    }
+}
+
+/// Whether the new container of a replica is started before or after the old
+/// one is stopped.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum UpdateOrder {
+    /// For replicas that can't run twice at the same time.
+    StopFirst,
+    /// The new container has to be healthy before the old one is stopped.
+    StartFirst,
+}
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum UpdateFailureAction {
+    Pause,
+    Continue,
+    Rollback,
 }
 
 #[derive(Clone, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
@@ -27,6 +104,8 @@ pub struct ImageBuildSpec {
     pub target: Option<String>,
     pub build_args: Map<String, String>,
     pub labels: Map<String, String>,
+    /// Size of `/dev/shm` in bytes during the build.
+    pub shm_size: Option<u64>,
 }
 
 #[derive(Clone, Debug, Hash)]
@@ -38,6 +117,17 @@ pub struct ImagePullSpec {
 pub enum PullPolicy {
     IfNotPresent,
     Always,
+    /// Images are never pulled, and have to be present already.
+    Never,
+}
+
+/// How `Controller::pull_images` reports progress and failures.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PullOptions {
+    /// Don't print the progress of pulls.
+    pub quiet: bool,
+    /// Warn about images that couldn't be pulled instead of failing.
+    pub ignore_failures: bool,
 }
 
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
@@ -46,6 +136,41 @@ pub enum BuildPolicy {
     Always,
 }
 
+#[derive(Clone, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub struct NetworkName(pub String);
+
+#[derive(Clone, Debug, Hash)]
+pub struct Network {
+    pub name: NetworkName,
+    pub labels: Map<String, String>,
+}
+
+#[derive(Clone, Debug, Hash)]
+pub struct NetworkSpec {
+    pub name: NetworkName,
+    /// External networks are managed by the user, they are never created or
+    /// removed, only required to exist.
+    pub external: bool,
+    pub driver: Option<String>,
+    pub internal: bool,
+    pub subnets: Vec<SubnetSpec>,
+    pub labels: Map<String, String>,
+}
+
+#[derive(Clone, Debug, Hash)]
+pub struct SubnetSpec {
+    pub subnet: String,
+    pub gateway: Option<String>,
+    pub ip_range: Option<String>,
+}
+
+/// How a container is connected to a network.
+#[derive(Clone, Debug, Default, Hash)]
+pub struct NetworkAttachment {
+    pub aliases: Vec<String>,
+    pub ipv4_address: Option<String>,
+}
+
 #[derive(Clone, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
 pub struct ContainerId(pub String);
 
@@ -64,14 +189,155 @@ pub struct Container {
 pub enum ContainerStatus {
     Configured,
     Running,
+    /// Podman is restarting the container because of its restart policy.
+    Restarting,
+    Paused,
+    /// The container is being stopped, but hasn't exited yet.
+    Stopping,
     Exited,
     Unknown,
 }
 
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum HealthStatus {
+    Healthy,
+    Unhealthy,
+}
+
 #[derive(Clone, Debug, Hash)]
 pub struct ContainerSpec {
     pub name: ContainerName,
     pub service_name: String,
+    /// The index of the replica within its service, starting at 0.
+    pub replica: u64,
     pub image_name: ImageName,
     pub labels: Map<String, String>,
+    /// Overrides the command of the image.
+    pub command: Option<Vec<String>>,
+    /// Overrides the entrypoint of the image, an empty entrypoint clears it.
+    pub entrypoint: Option<Vec<String>>,
+    pub working_dir: Option<String>,
+    pub user: Option<String>,
+    pub hostname: Option<String>,
+    pub domainname: Option<String>,
+    pub tty: bool,
+    pub stdin_open: bool,
+    pub resources: Resources,
+    pub security: Security,
+    pub podman: PodmanOptions,
+    /// Overrides the healthcheck defined by the image, if any.
+    pub healthcheck: Option<Healthcheck>,
+    pub restart_policy: RestartPolicy,
+    pub networks: Map<NetworkName, NetworkAttachment>,
+    /// Extra entries for `/etc/hosts`, in the form `hostname:ip`.
+    pub extra_hosts: Vec<String>,
+}
+
+/// Resource limits of a container, `None` means that podman's default is
+/// used.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct Resources {
+    /// Memory limit in bytes.
+    pub memory: Option<u64>,
+    /// Soft memory limit in bytes.
+    pub memory_reservation: Option<u64>,
+    /// Memory plus swap limit in bytes, -1 for unlimited swap.
+    pub memory_swap: Option<i64>,
+    pub memory_swappiness: Option<i64>,
+    /// CPU limit in billionths of a CPU, so that it can be hashed.
+    pub nano_cpus: Option<u64>,
+    pub cpu_shares: Option<u64>,
+    pub cpu_period: Option<Duration>,
+    pub cpu_quota: Option<Duration>,
+    pub cpuset: Option<String>,
+    /// Maximum number of processes, -1 for unlimited.
+    pub pids_limit: Option<i64>,
+    /// Size of `/dev/shm` in bytes.
+    pub shm_size: Option<u64>,
+    pub oom_score_adj: Option<i64>,
+}
+
+/// Privileges and kernel settings of a container, the values are in the
+/// format podman expects.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct Security {
+    pub cap_add: Vec<String>,
+    pub cap_drop: Vec<String>,
+    pub privileged: bool,
+    pub read_only: bool,
+    /// Options like `label=disable` or `seccomp=unconfined`.
+    pub security_opt: Vec<String>,
+    pub userns_mode: Option<String>,
+    pub group_add: Vec<String>,
+    /// Devices in the form `host[:container[:permissions]]`.
+    pub devices: Vec<String>,
+    /// Kernel parameters in the form `name=value`.
+    pub sysctls: Vec<String>,
+    /// Limits in the form `name=soft:hard`.
+    pub ulimits: Vec<String>,
+    /// Mounts in the form `path[:options]`.
+    pub tmpfs: Vec<String>,
+}
+
+/// Settings that only podman has, from the `x-pod-compose` extension.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct PodmanOptions {
+    /// `true`, `false` or `always`, podman's default is `true`.
+    pub systemd: Option<String>,
+    /// User namespace mappings in the form `container:host:size`.
+    pub uidmap: Vec<String>,
+    /// Group namespace mappings in the form `container:host:size`.
+    pub gidmap: Vec<String>,
+    /// OCI annotations in the form `key=value`.
+    pub annotations: Vec<String>,
+}
+
+#[derive(Copy, Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    Always,
+    OnFailure {
+        max_retries: Option<u64>,
+    },
+    UnlessStopped,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Healthcheck {
+    pub test: HealthcheckTest,
+    pub interval: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub retries: Option<u64>,
+    pub start_period: Option<Duration>,
+}
+
+impl Healthcheck {
+    /// The same defaults as podman uses.
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+    pub const DEFAULT_RETRIES: u64 = 3;
+
+    /// A healthcheck that disables the one defined by the image.
+    pub fn disabled() -> Healthcheck {
+        Healthcheck {
+            test: HealthcheckTest::None,
+            interval: None,
+            timeout: None,
+            retries: None,
+            start_period: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.test != HealthcheckTest::None
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum HealthcheckTest {
+    /// Runs the command directly, without a shell.
+    Command(Vec<String>),
+    /// Runs the command with the container's default shell.
+    Shell(String),
+    None,
 }
@@ -1,9 +1,11 @@
-use anyhow::Result;
-use std::{collections::BTreeMap as Map, path::Path};
+use std::{collections::BTreeMap as Map, net::IpAddr, path::Path};
 
-use crate::models::{
-    Composition, Container, ContainerId, ContainerName, ContainerSpec, Image, ImageBuildSpec,
-    ImageId, ImageName, PullPolicy,
+use crate::{
+    errors::Result,
+    models::{
+        Composition, Container, ContainerId, ContainerName, ContainerSpec, HealthStatus, Image,
+        ImageBuildSpec, ImageId, ImageName, Network, NetworkName, NetworkSpec, PullPolicy,
+    },
 };
 
 /// A frontend that reads a container spec file such as `docker-compose.yml`.
@@ -18,7 +20,12 @@ pub trait ComposerFrontend {
 pub trait ContainerBackend {
     fn get_image(&mut self, name: &ImageName) -> Result<Option<Image>>;
 
-    fn pull_image(&mut self, name: &ImageName) -> Result<ImageId>;
+    /// Pulls an image, printing its progress unless `quiet` is set.
+    fn pull_image(&mut self, name: &ImageName, quiet: bool) -> Result<ImageId>;
+
+    /// Pushes an image to the registry it's named after, printing its
+    /// progress unless `quiet` is set.
+    fn push_image(&mut self, name: &ImageName, quiet: bool) -> Result<()>;
 
     fn build_image(&mut self, spec: &ImageBuildSpec, pull_policy: PullPolicy) -> Result<ImageId>;
 
@@ -27,11 +34,44 @@ pub trait ContainerBackend {
         labels: Vec<(&str, &str)>,
     ) -> Result<Map<ContainerName, Container>>;
 
+    fn get_container(&mut self, name: &str) -> Result<Option<Container>>;
+
     fn create_container(&mut self, spec: ContainerSpec) -> Result<ContainerId>;
 
     fn start_container(&mut self, name: &str) -> Result<ContainerId>;
 
     fn stop_container(&mut self, name: &str, timeout: u32) -> Result<ContainerId>;
 
-    fn remove_container(&mut self, name: &str, remove_volumes: bool) -> Result<ContainerId>;
+    fn unpause_container(&mut self, name: &str) -> Result<ContainerId>;
+
+    /// Waits until a container that is being stopped has exited.
+    fn wait_container(&mut self, name: &str) -> Result<()>;
+
+    /// Removes a container, `force` also removes it if it's running.
+    fn remove_container(
+        &mut self,
+        name: &str,
+        force: bool,
+        remove_volumes: bool,
+    ) -> Result<ContainerId>;
+
+    /// Returns the IP address of a running container, if it has one.
+    fn container_address(&mut self, name: &str) -> Result<Option<IpAddr>>;
+
+    /// Returns the networks a container is connected to.
+    fn container_networks(&mut self, name: &str) -> Result<Vec<NetworkName>>;
+
+    /// Runs the healthcheck of a container once and returns the result.
+    fn run_healthcheck(&mut self, name: &str) -> Result<HealthStatus>;
+
+    /// Lists all networks with the given labels.
+    fn list_networks(&mut self, labels: Vec<(&str, &str)>) -> Result<Map<NetworkName, Network>>;
+
+    fn create_network(&mut self, spec: &NetworkSpec) -> Result<()>;
+
+    fn remove_network(&mut self, name: &NetworkName) -> Result<()>;
+
+    /// Whether containers can be given aliases on the given networks, making
+    /// them reachable by other names than their own.
+    fn supports_network_aliases(&mut self, networks: &[NetworkName]) -> Result<bool>;
 }
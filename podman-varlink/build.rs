@@ -1,3 +1,97 @@
+use std::{env, fmt::Write, fs, path::Path};
+
+/// Structs that are passed to podman, and get a `Default` impl and builder
+/// methods so that callers only have to set the fields they care about.
+const INPUT_TYPES: &[&str] = &["AuthConfig", "BuildInfo", "BuildOptions", "Create"];
+
 fn main() {
     varlink_generator::cargo_build_tosource("src/io.podman.varlink", true);
+
+    let source = fs::read_to_string("src/io_podman.rs").expect("failed to read generated source");
+
+    let mut builders = String::new();
+    for name in INPUT_TYPES {
+        let fields = struct_fields(&source, name);
+        write_builder(&mut builders, name, &fields).unwrap();
+    }
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("builders.rs"), builders).expect("failed to write builders");
+}
+
+/// Returns the names and types of the fields of a generated struct.
+fn struct_fields(source: &str, name: &str) -> Vec<(String, String)> {
+    let header = format!("pub struct r#{} {{", name);
+
+    let mut lines = source.lines().skip_while(|line| line.trim() != header);
+    if lines.next().is_none() {
+        panic!("struct {} is missing from the generated source", name);
+    }
+
+    lines
+        .take_while(|line| line.trim() != "}")
+        .filter_map(|line| line.trim().strip_prefix("pub r#"))
+        .map(|field| {
+            let (field, ty) = field.split_at(field.find(':').unwrap());
+            (
+                field.to_owned(),
+                ty[1..].trim().trim_end_matches(',').to_owned(),
+            )
+        })
+        .collect()
+}
+
+fn write_builder(out: &mut String, name: &str, fields: &[(String, String)]) -> std::fmt::Result {
+    writeln!(out, "impl Default for {} {{", name)?;
+    writeln!(out, "    fn default() -> Self {{")?;
+    writeln!(out, "        {} {{", name)?;
+    for (field, _) in fields {
+        writeln!(out, "            r#{}: Default::default(),", field)?;
+    }
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+
+    writeln!(out, "impl {} {{", name)?;
+    for (field, ty) in fields {
+        // Optional fields accept both a value and an `Option`.
+        let (ty, value) = match ty.strip_prefix("Option<") {
+            Some(_) => (format!("impl Into<{}>", ty), "value.into()"),
+            None => (ty.clone(), "value"),
+        };
+
+        writeln!(out, "    /// Sets `{}`.", field)?;
+        writeln!(
+            out,
+            "    pub fn r#{}(mut self, value: {}) -> Self {{",
+            snake_case(field),
+            ty
+        )?;
+        writeln!(out, "        self.r#{} = {};", field, value)?;
+        writeln!(out, "        self")?;
+        writeln!(out, "    }}")?;
+    }
+    writeln!(out, "}}")
+}
+
+/// Converts a field name like `cpuSetCpus` or `overrideOS` to `cpu_set_cpus`
+/// and `override_os`.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    let mut previous_upper = true;
+
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            if !previous_upper {
+                snake.push('_');
+            }
+            snake.push(c.to_ascii_lowercase());
+            previous_upper = true;
+        } else {
+            snake.push(c);
+            previous_upper = false;
+        }
+    }
+
+    snake
 }
@@ -0,0 +1,273 @@
+use std::{
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+use crate::io_podman::{
+    AuthConfig, BuildInfo, Container, Create, Error, ErrorKind, Image, MoreResponse, VarlinkClient,
+    VarlinkClientInterface,
+};
+
+/// Errors returned by `Podman`, with the details podman sent along with
+/// them.
+#[derive(Debug)]
+pub enum PodmanError {
+    ContainerNotFound {
+        id: String,
+        reason: String,
+    },
+    ImageNotFound {
+        id: String,
+        reason: String,
+    },
+    InvalidState {
+        id: String,
+        reason: String,
+    },
+    /// Podman failed to carry out the call.
+    Failed {
+        reason: String,
+    },
+    /// A build or pull finished without reporting an image id.
+    MissingImageId,
+    /// The varlink connection to podman failed.
+    Connection(Error),
+    /// Any other error reply from podman.
+    Other(Error),
+}
+
+impl fmt::Display for PodmanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PodmanError::ContainerNotFound { id, reason } => {
+                write!(f, "container {} not found: {}", id, reason)
+            }
+            PodmanError::ImageNotFound { id, reason } => {
+                write!(f, "image {} not found: {}", id, reason)
+            }
+            PodmanError::InvalidState { id, reason } => {
+                write!(f, "{} is in an invalid state: {}", id, reason)
+            }
+            PodmanError::Failed { reason } => write!(f, "{}", reason),
+            PodmanError::MissingImageId => write!(f, "podman did not report an image id"),
+            PodmanError::Connection(err) => write!(f, "connection to podman failed: {}", err),
+            PodmanError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for PodmanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PodmanError::Connection(err) | PodmanError::Other(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for PodmanError {
+    fn from(err: Error) -> Self {
+        match err.kind().clone() {
+            ErrorKind::ContainerNotFound(Some(args)) => PodmanError::ContainerNotFound {
+                id: args.id,
+                reason: args.reason,
+            },
+            ErrorKind::ImageNotFound(Some(args)) => PodmanError::ImageNotFound {
+                id: args.id,
+                reason: args.reason,
+            },
+            ErrorKind::InvalidState(Some(args)) => PodmanError::InvalidState {
+                id: args.id,
+                reason: args.reason,
+            },
+            ErrorKind::ErrorOccurred(Some(args)) => PodmanError::Failed {
+                reason: args.reason,
+            },
+            ErrorKind::RuntimeError(Some(args)) => PodmanError::Failed {
+                reason: args.reason,
+            },
+            ErrorKind::Varlink_Error => PodmanError::Connection(err),
+            _ => PodmanError::Other(err),
+        }
+    }
+}
+
+impl From<varlink::Error> for PodmanError {
+    fn from(err: varlink::Error) -> Self {
+        PodmanError::from(Error::from(err))
+    }
+}
+
+pub type PodmanResult<T> = std::result::Result<T, PodmanError>;
+
+/// A typed wrapper around `VarlinkClient` for the calls pod-compose needs.
+/// Replies are unwrapped and errors are turned into `PodmanError`s.
+pub struct Podman {
+    client: VarlinkClient,
+}
+
+impl Podman {
+    pub fn new(connection: Arc<RwLock<varlink::Connection>>) -> Podman {
+        Podman {
+            client: VarlinkClient::new(connection),
+        }
+    }
+
+    /// Returns the version of the podman service, like `2.2.1`.
+    pub fn get_version(&mut self) -> PodmanResult<String> {
+        let reply = self.client.get_version().call()?;
+        Ok(reply.version)
+    }
+
+    pub fn get_image(&mut self, name: &str) -> PodmanResult<Image> {
+        let reply = self.client.get_image(name.to_owned()).call()?;
+        Ok(reply.image)
+    }
+
+    /// Pulls an image, passing its progress output to `log`. Returns the id
+    /// of the pulled image.
+    pub fn pull_image(
+        &mut self,
+        name: &str,
+        auth_config: AuthConfig,
+        log: impl FnMut(&str),
+    ) -> PodmanResult<String> {
+        let mut call = self.client.pull_image(name.to_owned(), auth_config);
+        let replies = call.more()?.map(|reply| reply.map(|reply| reply.reply));
+
+        more_image_id(replies, log)
+    }
+
+    /// Pushes an image to `destination`, passing its progress output to
+    /// `log`. The call doesn't take credentials, podman reads them from its
+    /// own `auth.json`.
+    pub fn push_image(
+        &mut self,
+        name: &str,
+        destination: &str,
+        mut log: impl FnMut(&str),
+    ) -> PodmanResult<()> {
+        let mut call = self.client.push_image(
+            name.to_owned(),
+            destination.to_owned(),
+            false,
+            String::new(),
+            false,
+            String::new(),
+        );
+
+        for reply in call.more()? {
+            for line in reply?.reply.logs.unwrap_or_default() {
+                log(&line);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds an image, passing the build output to `log`. Returns the id of
+    /// the built image.
+    pub fn build_image(
+        &mut self,
+        build_info: BuildInfo,
+        log: impl FnMut(&str),
+    ) -> PodmanResult<String> {
+        let mut call = self.client.build_image(build_info);
+        let replies = call.more()?.map(|reply| reply.map(|reply| reply.image));
+
+        more_image_id(replies, log)
+    }
+
+    pub fn list_containers(&mut self) -> PodmanResult<Vec<Container>> {
+        let reply = self.client.list_containers().call()?;
+        Ok(reply.containers.unwrap_or_default())
+    }
+
+    pub fn get_container(&mut self, name: &str) -> PodmanResult<Container> {
+        let reply = self.client.get_container(name.to_owned()).call()?;
+        Ok(reply.container)
+    }
+
+    /// Returns the JSON output of `podman inspect` for a container.
+    pub fn inspect_container(&mut self, name: &str) -> PodmanResult<String> {
+        let reply = self.client.inspect_container(name.to_owned()).call()?;
+        Ok(reply.container)
+    }
+
+    /// Creates a container and returns its id.
+    pub fn create_container(&mut self, create: Create) -> PodmanResult<String> {
+        let reply = self.client.create_container(create).call()?;
+        Ok(reply.container)
+    }
+
+    pub fn start_container(&mut self, name: &str) -> PodmanResult<String> {
+        let reply = self.client.start_container(name.to_owned()).call()?;
+        Ok(reply.container)
+    }
+
+    pub fn stop_container(&mut self, name: &str, timeout: i64) -> PodmanResult<String> {
+        let reply = self
+            .client
+            .stop_container(name.to_owned(), timeout)
+            .call()?;
+        Ok(reply.container)
+    }
+
+    pub fn unpause_container(&mut self, name: &str) -> PodmanResult<String> {
+        let reply = self.client.unpause_container(name.to_owned()).call()?;
+        Ok(reply.container)
+    }
+
+    /// Waits until a container stops, checking every `interval`
+    /// milliseconds, and returns its exit code.
+    pub fn wait_container(&mut self, name: &str, interval: i64) -> PodmanResult<i64> {
+        let reply = self
+            .client
+            .wait_container(name.to_owned(), interval)
+            .call()?;
+        Ok(reply.exitcode)
+    }
+
+    pub fn remove_container(
+        &mut self,
+        name: &str,
+        force: bool,
+        remove_volumes: bool,
+    ) -> PodmanResult<String> {
+        let reply = self
+            .client
+            .remove_container(name.to_owned(), force, remove_volumes)
+            .call()?;
+        Ok(reply.container)
+    }
+
+    /// Runs the healthcheck of a container and returns podman's status for
+    /// it, like `healthy` or `unhealthy`.
+    pub fn health_check_run(&mut self, name: &str) -> PodmanResult<String> {
+        let reply = self.client.health_check_run(name.to_owned()).call()?;
+        Ok(reply.healthCheckStatus)
+    }
+}
+
+/// Collects the logs and the final image id from the replies of a call that
+/// streams its progress.
+fn more_image_id(
+    replies: impl Iterator<Item = crate::io_podman::Result<MoreResponse>>,
+    mut log: impl FnMut(&str),
+) -> PodmanResult<String> {
+    let mut image_id = None;
+
+    for reply in replies {
+        let reply = reply?;
+
+        for line in reply.logs.unwrap_or_default() {
+            log(&line);
+        }
+
+        if !reply.id.is_empty() {
+            image_id = Some(reply.id);
+        }
+    }
+
+    image_id.ok_or(PodmanError::MissingImageId)
+}
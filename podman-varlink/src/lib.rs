@@ -1,3 +1,15 @@
+pub use client::{Podman, PodmanError, PodmanResult};
 pub use io_podman::*;
 
+mod client;
 mod io_podman;
+
+/// `Default` impls and builder methods for the input structs, generated by
+/// the build script. They can't be derived since the structs themselves are
+/// generated by varlink_generator.
+#[allow(clippy::derivable_impls)]
+mod builders {
+    use super::io_podman::*;
+
+    include!(concat!(env!("OUT_DIR"), "/builders.rs"));
+}